@@ -0,0 +1,109 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+const SVG = 'http://www.w3.org/2000/svg';
+const numbers = new Intl.NumberFormat();
+
+function element(name, attributes = {}, namespace) {
+    const el = namespace ? document.createElementNS(namespace, name) : document.createElement(name);
+    for (const [key, value] of Object.entries(attributes)) {
+        el.setAttribute(key, value);
+    }
+
+    return el;
+}
+
+function renderVolume(overview) {
+    const svg = document.getElementById('volume');
+    svg.replaceChildren();
+
+    const points = overview.volume;
+    const max = Math.max(1, ...points.map((p) => p.count));
+    const width = 800 / Math.max(1, points.length);
+
+    points.forEach((point, i) => {
+        const height = (point.count / max) * 230;
+        const rect = element('rect', {
+            x: i * width + width * 0.1,
+            y: 240 - height,
+            width: width * 0.8,
+            height
+        }, SVG);
+
+        const title = element('title', {}, SVG);
+        title.textContent = `${new Date(point.time).toLocaleString()}: ${numbers.format(point.count)} events`;
+        rect.appendChild(title);
+        svg.appendChild(rect);
+    });
+
+    document.getElementById('total').textContent = `(${numbers.format(overview.total)})`;
+}
+
+function renderBreakdown(dimension, values) {
+    const table = document.getElementById(`${dimension}-breakdown`);
+    table.replaceChildren();
+
+    const max = Math.max(1, ...values.map((v) => v.count));
+    for (const { value, count } of values.slice(0, 10)) {
+        const row = element('tr');
+        const name = element('td');
+        name.textContent = value ?? '(unknown)';
+
+        const bar = element('div', { class: 'bar' });
+        bar.appendChild(element('span', { style: `width: ${(count / max) * 100}%` }));
+        name.appendChild(bar);
+
+        const total = element('td', { class: 'count' });
+        total.textContent = numbers.format(count);
+
+        row.append(name, total);
+        table.appendChild(row);
+    }
+}
+
+async function load() {
+    const params = new URLSearchParams({ days: document.getElementById('days').value });
+    const product = document.getElementById('product').value.trim();
+    if (product) {
+        params.set('product', product);
+    }
+
+    const error = document.getElementById('error');
+    const res = await fetch(`/dashboard/api/overview?${params}`, { credentials: 'same-origin' });
+    if (res.status === 401) {
+        window.location.reload();
+        return;
+    }
+
+    const body = await res.json();
+    if (!body.success) {
+        error.textContent = body.errors.map((e) => e.message).join(', ');
+        error.hidden = false;
+        return;
+    }
+
+    error.hidden = true;
+    renderVolume(body.data);
+    for (const dimension of ['product', 'version', 'os', 'arch']) {
+        renderBreakdown(dimension, body.data.breakdowns[dimension] ?? []);
+    }
+}
+
+document.getElementById('filters').addEventListener('submit', (event) => {
+    event.preventDefault();
+    load();
+});
+
+load();
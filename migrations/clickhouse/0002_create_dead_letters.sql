@@ -0,0 +1,34 @@
+-- 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+-- Copyright 2022 Noelware <team@noelware.org>
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+CREATE TABLE IF NOT EXISTS telemetry.dead_letters(
+    -- The ID of the rejected event.
+    ID UInt64,
+
+    -- At what time the server received the event.
+    ReceivedAt DateTime('UTC'),
+
+    -- Why the event was rejected (INVALID_JSON, VALIDATION_FAILED, INSERT_FAILED)
+    Reason String,
+
+    -- The product that sent this event, if it could be determined.
+    Product Nullable(String),
+
+    -- Error message that explains the rejection.
+    Message String,
+
+    -- The raw request body.
+    Body String
+) ENGINE=MergeTree() ORDER BY (ReceivedAt, ID);
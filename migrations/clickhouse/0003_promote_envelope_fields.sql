@@ -0,0 +1,91 @@
+-- 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+-- Copyright 2022 Noelware <team@noelware.org>
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- Promotes the fields that used to be packed in the `Data` JSON string into their own
+-- columns, and partitions the table by month. ClickHouse can't change the partition key
+-- of an existing table, so the rows are copied into a new table that takes the place of
+-- `telemetry.events`. The old table is kept as `telemetry.events_legacy` and can be
+-- dropped by hand once you're happy with the backfill.
+--
+-- ClickHouse has no transactional DDL, so every step is skipped once it is done, and the
+-- migration picks up where it left off if it was interrupted. The backfill is done once
+-- `telemetry.events` has the new columns, which the tables are swapped for atomically.
+-- Events that were stored while the rows were being copied end up in the old table, so
+-- they're copied once more after the swap. Servers that still run a version from before
+-- this migration should be upgraded with it, since they don't know about the new columns.
+-- unless exists: telemetry.events.Distribution
+CREATE TABLE IF NOT EXISTS telemetry.events_v2(
+    -- The ID of the telemetry event.
+    ID UInt64,
+
+    -- The product that was used for this telemetry event.
+    Product String,
+
+    -- The vendor, always "Noelware"
+    Vendor String,
+
+    -- How the product was distributed (i.e, "docker", "helm", "git")
+    Distribution LowCardinality(String),
+
+    -- The version of the product.
+    Version LowCardinality(String),
+
+    -- CPU architecture the product is running on.
+    Arch LowCardinality(String),
+
+    -- Operating system the product is running on.
+    OS LowCardinality(String),
+
+    -- At what time this telemetry event was fired at.
+    FiredAt DateTime64(3, 'UTC'),
+
+    -- The data object that the product sent, as a JSON string.
+    Data String
+) ENGINE=MergeTree() PARTITION BY toYYYYMM(FiredAt) ORDER BY (Product, FiredAt, ID);
+
+-- unless exists: telemetry.events.Distribution
+INSERT INTO telemetry.events_v2 (ID, Product, Vendor, Distribution, Version, Arch, OS, FiredAt, Data)
+SELECT
+    ID,
+    Product,
+    Vendor,
+    JSONExtractString(Data, 'distribution'),
+    JSONExtractString(Data, 'version'),
+    JSONExtractString(Data, 'arch'),
+    JSONExtractString(Data, 'os'),
+    parseDateTime64BestEffortOrZero(JSONExtractString(Data, 'fired_at'), 3, 'UTC'),
+    JSONExtractRaw(Data, 'data')
+FROM telemetry.events
+WHERE ID NOT IN (SELECT ID FROM telemetry.events_v2);
+
+-- unless exists: telemetry.events.Distribution
+EXCHANGE TABLES telemetry.events AND telemetry.events_v2;
+
+-- unless exists: telemetry.events_legacy
+RENAME TABLE telemetry.events_v2 TO telemetry.events_legacy;
+
+INSERT INTO telemetry.events (ID, Product, Vendor, Distribution, Version, Arch, OS, FiredAt, Data)
+SELECT
+    ID,
+    Product,
+    Vendor,
+    JSONExtractString(Data, 'distribution'),
+    JSONExtractString(Data, 'version'),
+    JSONExtractString(Data, 'arch'),
+    JSONExtractString(Data, 'os'),
+    parseDateTime64BestEffortOrZero(JSONExtractString(Data, 'fired_at'), 3, 'UTC'),
+    JSONExtractRaw(Data, 'data')
+FROM telemetry.events_legacy
+WHERE ID NOT IN (SELECT ID FROM telemetry.events);
@@ -0,0 +1,21 @@
+-- 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+-- Copyright 2022 Noelware <team@noelware.org>
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- Anonymous identifier that products generate for each installation, so events of the
+-- same installation can be related to each other. Events that don't have one store an
+-- empty string.
+ALTER TABLE telemetry.events ADD COLUMN IF NOT EXISTS InstallationID String DEFAULT '' AFTER Vendor;
+
+ALTER TABLE telemetry.events ADD INDEX IF NOT EXISTS events_installation_id InstallationID TYPE bloom_filter GRANULARITY 4;
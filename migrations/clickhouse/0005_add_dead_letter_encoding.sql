@@ -0,0 +1,18 @@
+-- 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+-- Copyright 2022 Noelware <team@noelware.org>
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- Bodies that aren't valid UTF-8 are stored as base64, so they can be replayed byte for
+-- byte. Dead letters that were stored before this are always UTF-8.
+ALTER TABLE telemetry.dead_letters ADD COLUMN IF NOT EXISTS Encoding String DEFAULT 'utf8' AFTER Body;
@@ -0,0 +1,62 @@
+-- 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+-- Copyright 2022 Noelware <team@noelware.org>
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- Hourly and daily rollups that count events by their envelope fields, so `/v1/counts`
+-- doesn't have to scan `telemetry.events`. They are kept in sync by materialized views,
+-- and events that were stored before a view was created are backfilled, unless the rollup
+-- already has them (i.e, when the migration is resumed).
+CREATE TABLE IF NOT EXISTS telemetry.events_hourly(
+    Time DateTime('UTC'),
+    Product String,
+    Version LowCardinality(String),
+    OS LowCardinality(String),
+    Arch LowCardinality(String),
+    Distribution LowCardinality(String),
+    Events UInt64
+) ENGINE=SummingMergeTree(Events) PARTITION BY toYYYYMM(Time) ORDER BY (Product, Time, Version, OS, Arch, Distribution);
+
+CREATE MATERIALIZED VIEW IF NOT EXISTS telemetry.events_hourly_mv TO telemetry.events_hourly AS
+SELECT toDateTime(toStartOfHour(toDateTime(FiredAt, 'UTC')), 'UTC') AS Time, Product, Version, OS, Arch, Distribution, count() AS Events
+FROM telemetry.events
+GROUP BY Time, Product, Version, OS, Arch, Distribution;
+
+INSERT INTO telemetry.events_hourly
+SELECT toDateTime(toStartOfHour(toDateTime(FiredAt, 'UTC')), 'UTC') AS Time, Product, Version, OS, Arch, Distribution, count() AS Events
+FROM telemetry.events
+WHERE FiredAt < (SELECT metadata_modification_time FROM system.tables WHERE database = 'telemetry' AND name = 'events_hourly_mv')
+    AND (SELECT count() FROM telemetry.events_hourly WHERE Time < toStartOfHour((SELECT metadata_modification_time FROM system.tables WHERE database = 'telemetry' AND name = 'events_hourly_mv'))) = 0
+GROUP BY Time, Product, Version, OS, Arch, Distribution;
+
+CREATE TABLE IF NOT EXISTS telemetry.events_daily(
+    Time DateTime('UTC'),
+    Product String,
+    Version LowCardinality(String),
+    OS LowCardinality(String),
+    Arch LowCardinality(String),
+    Distribution LowCardinality(String),
+    Events UInt64
+) ENGINE=SummingMergeTree(Events) PARTITION BY toYYYYMM(Time) ORDER BY (Product, Time, Version, OS, Arch, Distribution);
+
+CREATE MATERIALIZED VIEW IF NOT EXISTS telemetry.events_daily_mv TO telemetry.events_daily AS
+SELECT toDateTime(toStartOfDay(toDateTime(FiredAt, 'UTC')), 'UTC') AS Time, Product, Version, OS, Arch, Distribution, count() AS Events
+FROM telemetry.events
+GROUP BY Time, Product, Version, OS, Arch, Distribution;
+
+INSERT INTO telemetry.events_daily
+SELECT toDateTime(toStartOfDay(toDateTime(FiredAt, 'UTC')), 'UTC') AS Time, Product, Version, OS, Arch, Distribution, count() AS Events
+FROM telemetry.events
+WHERE FiredAt < (SELECT metadata_modification_time FROM system.tables WHERE database = 'telemetry' AND name = 'events_daily_mv')
+    AND (SELECT count() FROM telemetry.events_daily WHERE Time < toStartOfDay((SELECT metadata_modification_time FROM system.tables WHERE database = 'telemetry' AND name = 'events_daily_mv'))) = 0
+GROUP BY Time, Product, Version, OS, Arch, Distribution;
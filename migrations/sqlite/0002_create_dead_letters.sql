@@ -0,0 +1,27 @@
+-- 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+-- Copyright 2022 Noelware <team@noelware.org>
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+CREATE TABLE IF NOT EXISTS dead_letters(
+    ID INTEGER NOT NULL PRIMARY KEY,
+
+    -- Unix timestamp (in milliseconds) of when the server received the event.
+    ReceivedAt INTEGER NOT NULL,
+    Reason TEXT NOT NULL,
+    Product TEXT,
+    Message TEXT NOT NULL,
+    Body TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS dead_letters_received_at ON dead_letters(ReceivedAt);
@@ -0,0 +1,35 @@
+-- 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+-- Copyright 2022 Noelware <team@noelware.org>
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- Promotes the fields that used to be packed in the `Data` JSON string into their own
+-- columns, and backfills them for existing rows.
+ALTER TABLE events ADD COLUMN Distribution TEXT;
+ALTER TABLE events ADD COLUMN Version TEXT;
+ALTER TABLE events ADD COLUMN Arch TEXT;
+ALTER TABLE events ADD COLUMN OS TEXT;
+
+-- Unix timestamp (in milliseconds) of when this telemetry event was fired at.
+ALTER TABLE events ADD COLUMN FiredAt INTEGER;
+
+UPDATE events SET
+    Distribution = json_extract(Data, '$.distribution'),
+    Version = json_extract(Data, '$.version'),
+    Arch = json_extract(Data, '$.arch'),
+    OS = json_extract(Data, '$.os'),
+    FiredAt = CAST(ROUND((julianday(json_extract(Data, '$.fired_at')) - 2440587.5) * 86400000) AS INTEGER),
+    Data = Data -> '$.data'
+WHERE FiredAt IS NULL;
+
+CREATE INDEX IF NOT EXISTS events_fired_at ON events(FiredAt);
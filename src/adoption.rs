@@ -0,0 +1,251 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    analytics::{self, EventQuery, Granularity},
+    storage::Event,
+};
+
+/// Query for the version adoption endpoint. Only events that have an `installation_id`
+/// are taken into account, since the others can't be told apart.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AdoptionQuery {
+    pub product: String,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub granularity: Option<Granularity>, // defaults to "day"
+    pub timezone: Option<String>,         // defaults to "UTC"
+}
+
+impl AdoptionQuery {
+    pub fn granularity(&self) -> Granularity {
+        self.granularity.unwrap_or(Granularity::Day)
+    }
+
+    pub fn tz(&self) -> Result<Tz, String> {
+        analytics::timezone(self.timezone.as_deref())
+    }
+
+    pub fn events(&self) -> EventQuery {
+        EventQuery {
+            product: Some(self.product.clone()),
+            from: self.from,
+            to: self.to,
+            ..Default::default()
+        }
+    }
+
+    /// Computes the adoption out of the given events, for the backends that can't do
+    /// it themselves.
+    pub fn compute(&self, events: Vec<Event>) -> Adoption {
+        let tz = self.tz().unwrap_or(Tz::UTC);
+        let mut installations = BTreeMap::<&str, Vec<&Event>>::new();
+        for event in events.iter() {
+            if let Some(id) = event.installation_id.as_deref() {
+                installations.entry(id).or_default().push(event);
+            }
+        }
+
+        // the version an installation was on in each bucket is the last one it reported.
+        let mut versions = BTreeMap::<(DateTime<Utc>, &str), &str>::new();
+        let mut transitions = BTreeMap::<(String, String), u64>::new();
+        for (id, mut events) in installations {
+            events.sort_by_key(|e| (e.fired_at, e.id));
+            for event in events.iter() {
+                let bucket = self
+                    .granularity()
+                    .truncate_in(event.fired_at, &tz)
+                    .with_timezone(&Utc);
+
+                versions.insert((bucket, id), &event.version);
+            }
+
+            // an installation is only counted once for each transition, even if it went
+            // back and forth between the same versions.
+            let mut seen = BTreeSet::new();
+            for pair in events.windows(2) {
+                let transition = (pair[0].version.clone(), pair[1].version.clone());
+                if transition.0 != transition.1 && seen.insert(transition.clone()) {
+                    *transitions.entry(transition).or_default() += 1;
+                }
+            }
+        }
+
+        let mut installs = BTreeMap::<(DateTime<Utc>, String), u64>::new();
+        for ((bucket, _), version) in versions {
+            *installs.entry((bucket, version.to_string())).or_default() += 1;
+        }
+
+        self.adoption(
+            installs
+                .into_iter()
+                .map(|((time, version), count)| (time, version, count)),
+            transitions
+                .into_iter()
+                .map(|((from, to), count)| (from, to, count)),
+        )
+    }
+
+    /// Builds the adoption out of `(bucket, version, installations)` and
+    /// `(from, to, installations)` rows.
+    pub fn adoption<A, T>(&self, installs: A, transitions: T) -> Adoption
+    where
+        A: IntoIterator<Item = (DateTime<Utc>, String, u64)>,
+        T: IntoIterator<Item = (String, String, u64)>,
+    {
+        let tz = self.tz().unwrap_or(Tz::UTC);
+        let mut buckets = BTreeMap::<DateTime<Utc>, Vec<(String, u64)>>::new();
+        for (time, version, count) in installs {
+            buckets.entry(time).or_default().push((version, count));
+        }
+
+        let mut transitions = transitions
+            .into_iter()
+            .map(|(from, to, count)| Transition {
+                from,
+                to,
+                installations: count,
+            })
+            .collect::<Vec<_>>();
+
+        transitions.sort_by(|a, b| {
+            b.installations
+                .cmp(&a.installations)
+                .then_with(|| (&a.from, &a.to).cmp(&(&b.from, &b.to)))
+        });
+
+        Adoption {
+            product: self.product.clone(),
+            granularity: self.granularity(),
+            timezone: tz.name().to_string(),
+            buckets: buckets
+                .into_iter()
+                .map(|(time, versions)| {
+                    let total = versions.iter().map(|(_, count)| count).sum::<u64>();
+                    AdoptionBucket {
+                        time: time.with_timezone(&tz).fixed_offset(),
+                        installations: total,
+                        versions: versions
+                            .into_iter()
+                            .map(|(version, count)| VersionShare {
+                                version,
+                                installations: count,
+                                share: count as f64 / total as f64,
+                            })
+                            .collect(),
+                    }
+                })
+                .collect(),
+            transitions,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct VersionShare {
+    pub version: String,
+    pub installations: u64,
+    pub share: f64,
+}
+
+/// Represents which versions the installations that were active in a bucket were on.
+#[derive(Serialize, Debug, Clone)]
+pub struct AdoptionBucket {
+    pub time: DateTime<FixedOffset>,
+    pub installations: u64,
+    pub versions: Vec<VersionShare>,
+}
+
+/// Represents how many installations went from a version to another one, based on
+/// consecutive events of the same installation. This can be an upgrade as well as a
+/// downgrade, since versions aren't necessarily semver.
+#[derive(Serialize, Debug, Clone)]
+pub struct Transition {
+    pub from: String,
+    pub to: String,
+    pub installations: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Adoption {
+    pub product: String,
+    pub granularity: Granularity,
+    pub timezone: String,
+    pub buckets: Vec<AdoptionBucket>,
+    pub transitions: Vec<Transition>,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use super::AdoptionQuery;
+    use crate::storage::Event;
+
+    #[test]
+    fn shares_versions_and_counts_transitions() {
+        let start = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+        let event = |id: u64, installation: &str, version: &str, days: i64| {
+            Event::test(id)
+                .with_installation(installation)
+                .with_version(version)
+                .fired_at(start + Duration::days(days))
+        };
+
+        let query = AdoptionQuery {
+            product: "charted-server".into(),
+            ..Default::default()
+        };
+
+        let adoption = query.compute(vec![
+            event(1, "a", "0.1.0", 0),
+            event(2, "a", "0.2.0", 0),
+            event(3, "b", "0.1.0", 0),
+            event(4, "a", "0.1.0", 1),
+            event(5, "a", "0.2.0", 1),
+            event(6, "b", "0.2.0", 1),
+            event(7, "b", "0.1.0", 1),
+            // events without an installation are left out
+            Event::test(8).fired_at(start),
+        ]);
+
+        // the version an installation was on in a bucket is the last one it reported
+        let first = &adoption.buckets[0];
+        assert_eq!(first.installations, 2);
+        assert_eq!(first.versions[0].version, "0.1.0");
+        assert_eq!(first.versions[0].share, 0.5);
+        assert_eq!(first.versions[1].version, "0.2.0");
+        assert_eq!(first.versions[1].share, 0.5);
+
+        // going back and forth is counted once for each installation, and downgrades
+        // are transitions too
+        let transitions = adoption
+            .transitions
+            .iter()
+            .map(|t| (t.from.as_str(), t.to.as_str(), t.installations))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            transitions,
+            vec![("0.1.0", "0.2.0", 2), ("0.2.0", "0.1.0", 2)]
+        );
+    }
+}
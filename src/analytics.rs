@@ -0,0 +1,620 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use chrono::{DateTime, Datelike, Duration, FixedOffset, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::storage::Event;
+
+/// Represents how wide each bucket of a time-series is.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Minute,
+    #[default]
+    Hour,
+    Day,
+    Week,
+}
+
+impl Granularity {
+    /// Granularities that have a rollup table, from the coarsest to the finest.
+    pub const ROLLUPS: [Granularity; 2] = [Granularity::Day, Granularity::Hour];
+
+    pub fn seconds(&self) -> i64 {
+        match self {
+            Granularity::Minute => 60,
+            Granularity::Hour => 60 * 60,
+            Granularity::Day => 24 * 60 * 60,
+            Granularity::Week => 7 * 24 * 60 * 60,
+        }
+    }
+
+    /// How many seconds the buckets are shifted by from the Unix epoch in UTC, since weeks
+    /// start on a Monday and the epoch was a Thursday.
+    pub fn epoch_offset(&self) -> i64 {
+        match self {
+            Granularity::Week => 3 * 24 * 60 * 60,
+            _ => 0,
+        }
+    }
+
+    /// Returns the start of the bucket the given time falls in.
+    pub fn truncate(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        self.truncate_in(time, &Utc)
+    }
+
+    /// Returns the start of the bucket the given time falls in, where buckets follow the
+    /// wall clock of the given timezone.
+    pub fn truncate_in<T: TimeZone>(&self, time: DateTime<Utc>, tz: &T) -> DateTime<T> {
+        let local = time.with_timezone(tz).naive_local();
+        let date = local.date();
+        let start = match self {
+            Granularity::Minute => date.and_hms_opt(local.hour(), local.minute(), 0),
+            Granularity::Hour => date.and_hms_opt(local.hour(), 0, 0),
+            Granularity::Day => date.and_hms_opt(0, 0, 0),
+            Granularity::Week => (date
+                - Duration::days(date.weekday().num_days_from_monday() as i64))
+            .and_hms_opt(0, 0, 0),
+        }
+        .unwrap();
+
+        // the start of the bucket might not exist if the clocks moved forward at that time.
+        tz.from_local_datetime(&start)
+            .earliest()
+            .unwrap_or_else(|| tz.from_utc_datetime(&start))
+    }
+
+    pub fn is_aligned(&self, time: DateTime<Utc>) -> bool {
+        self.truncate(time) == time
+    }
+}
+
+/// Query for the time-series counts endpoint.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct CountsQuery {
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub os: Option<String>,
+    pub arch: Option<String>,
+    pub distribution: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub granularity: Option<Granularity>, // defaults to "hour"
+}
+
+impl CountsQuery {
+    pub fn granularity(&self) -> Granularity {
+        self.granularity.unwrap_or_default()
+    }
+
+    /// Returns the filters on the envelope fields as `(column, value)` pairs, with the
+    /// column names the storage backends use.
+    pub fn filters(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("Product", &self.product),
+            ("Version", &self.version),
+            ("OS", &self.os),
+            ("Arch", &self.arch),
+            ("Distribution", &self.distribution),
+        ]
+        .into_iter()
+        .filter_map(|(column, value)| value.as_deref().map(|value| (column, value)))
+        .collect()
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        self.filters()
+            .into_iter()
+            .all(|(column, value)| column_matches(event, column, value))
+            && self.from.is_none_or(|from| event.fired_at >= from)
+            && self.to.is_none_or(|to| event.fired_at < to)
+    }
+
+    /// Returns the coarsest rollup that can answer this query without losing precision,
+    /// which is only possible if the range starts and ends on the rollup's buckets.
+    pub fn rollup(&self) -> Option<Granularity> {
+        Granularity::ROLLUPS.into_iter().find(|rollup| {
+            *rollup <= self.granularity()
+                && self.from.is_none_or(|from| rollup.is_aligned(from))
+                && self.to.is_none_or(|to| rollup.is_aligned(to))
+        })
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CountBucket {
+    pub time: DateTime<Utc>,
+    pub count: u64,
+}
+
+/// Represents a time-series of event counts, and where it was computed from.
+#[derive(Serialize, Debug, Clone)]
+pub struct Counts {
+    pub granularity: Granularity,
+    pub source: &'static str,
+    pub buckets: Vec<CountBucket>,
+}
+
+/// Parses a JSON path into the `data` object, which is a list of keys separated by
+/// dots (i.e, `cluster.nodes`). Keys can only have letters, digits, `_` and `-`, so they
+/// are safe to put in any backend's path syntax.
+pub fn parse_path(path: &str) -> Result<Vec<String>, String> {
+    let keys = path.split('.').map(String::from).collect::<Vec<_>>();
+    for key in keys.iter() {
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!("invalid JSON path `{}`", path));
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Parses an IANA timezone name (i.e, `Europe/Berlin`), which defaults to UTC.
+pub fn timezone(name: Option<&str>) -> Result<Tz, String> {
+    match name {
+        Some(name) => Tz::from_str(name).map_err(|_| format!("unknown timezone `{}`", name)),
+        None => Ok(Tz::UTC),
+    }
+}
+
+/// Returns the value at the given path in the `data` object.
+pub fn lookup<'a>(data: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(data, |value, key| value.get(key))
+}
+
+/// Returns how a JSON value is compared against the values in the query string: strings
+/// as-is, and everything else as JSON (i.e, `5`, `true`).
+pub fn json_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Represents a filter on a JSON path in the `data` object, which is given as
+/// `data.<path>=<value>` in the query string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataFilter {
+    pub path: Vec<String>,
+    pub value: String,
+}
+
+impl DataFilter {
+    /// Collects the `data.<path>` filters out of the query string's pairs.
+    pub fn from_pairs(pairs: &[(String, String)]) -> Result<Vec<DataFilter>, String> {
+        pairs
+            .iter()
+            .filter_map(|(key, value)| key.strip_prefix("data.").map(|path| (path, value)))
+            .map(|(path, value)| {
+                Ok(DataFilter {
+                    path: parse_path(path)?,
+                    value: value.clone(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn matches(&self, data: &Value) -> bool {
+        lookup(data, &self.path).is_some_and(|value| json_text(value) == self.value)
+    }
+}
+
+/// Query for listing events, newest first.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct EventQuery {
+    pub product: Option<String>,
+    pub vendor: Option<String>,
+    pub version: Option<String>,
+    pub os: Option<String>,
+    pub arch: Option<String>,
+    pub distribution: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub cursor: Option<String>, // the `next_cursor` of the previous page
+    pub limit: Option<u64>,
+
+    /// Only return events with an ID lower than this, which is decoded from the cursor.
+    #[serde(skip)]
+    pub before: Option<u64>,
+
+    #[serde(skip)]
+    pub data: Vec<DataFilter>,
+}
+
+impl EventQuery {
+    pub fn limit(&self) -> u64 {
+        self.limit.unwrap_or(50).clamp(1, 500)
+    }
+
+    /// Returns the filters on the envelope fields as `(column, value)` pairs, with the
+    /// column names the storage backends use.
+    pub fn filters(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("Product", &self.product),
+            ("Vendor", &self.vendor),
+            ("Version", &self.version),
+            ("OS", &self.os),
+            ("Arch", &self.arch),
+            ("Distribution", &self.distribution),
+        ]
+        .into_iter()
+        .filter_map(|(column, value)| value.as_deref().map(|value| (column, value)))
+        .collect()
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        self.filters()
+            .into_iter()
+            .all(|(column, value)| column_matches(event, column, value))
+            && self.from.is_none_or(|from| event.fired_at >= from)
+            && self.to.is_none_or(|to| event.fired_at < to)
+            && self.before.is_none_or(|before| event.id < before)
+            && self.data.iter().all(|filter| filter.matches(&event.data))
+    }
+}
+
+/// Represents what events can be grouped by in the aggregation API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Dimension {
+    Product,
+    Version,
+    OS,
+    Arch,
+    Distribution,
+    Data(Vec<String>),
+}
+
+impl FromStr for Dimension {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "product" => Ok(Dimension::Product),
+            "version" => Ok(Dimension::Version),
+            "os" => Ok(Dimension::OS),
+            "arch" => Ok(Dimension::Arch),
+            "distribution" => Ok(Dimension::Distribution),
+            s => match s.strip_prefix("data.") {
+                Some(path) => Ok(Dimension::Data(parse_path(path)?)),
+                None => Err(format!("can't group events by `{}`", s)),
+            },
+        }
+    }
+}
+
+impl Dimension {
+    /// Parses a comma-separated list of dimensions, i.e. `product,version,data.cluster.nodes`.
+    pub fn parse_list(list: &str) -> Result<Vec<Dimension>, String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(Dimension::from_str)
+            .collect()
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Dimension::Product => "product".into(),
+            Dimension::Version => "version".into(),
+            Dimension::OS => "os".into(),
+            Dimension::Arch => "arch".into(),
+            Dimension::Distribution => "distribution".into(),
+            Dimension::Data(path) => format!("data.{}", path.join(".")),
+        }
+    }
+
+    pub fn value(&self, event: &Event) -> Option<String> {
+        match self {
+            Dimension::Product => Some(event.product.clone()),
+            Dimension::Version => Some(event.version.clone()),
+            Dimension::OS => Some(event.os.clone()),
+            Dimension::Arch => Some(event.arch.clone()),
+            Dimension::Distribution => Some(event.distribution.clone()),
+            Dimension::Data(path) => lookup(&event.data, path).map(json_text),
+        }
+    }
+}
+
+/// Query for the aggregation API, which counts events in each bucket for every
+/// combination of the dimensions they are grouped by.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AggregateQuery {
+    pub product: Option<String>,
+    pub vendor: Option<String>,
+    pub version: Option<String>,
+    pub os: Option<String>,
+    pub arch: Option<String>,
+    pub distribution: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub group_by: Option<String>, // comma-separated list of dimensions
+    pub granularity: Option<Granularity>, // defaults to "hour"
+    pub timezone: Option<String>, // defaults to "UTC"
+
+    #[serde(skip)]
+    pub dimensions: Vec<Dimension>,
+
+    #[serde(skip)]
+    pub data: Vec<DataFilter>,
+}
+
+impl AggregateQuery {
+    /// Parses the `group_by` and `timezone` parameters, which are kept as strings
+    /// so that the errors can say what was wrong with them.
+    pub fn validate(&mut self) -> Result<(), (&'static str, String)> {
+        self.dimensions = Dimension::parse_list(self.group_by.as_deref().unwrap_or_default())
+            .map_err(|e| ("INVALID_GROUP_BY", e))?;
+
+        self.tz().map_err(|e| ("INVALID_TIMEZONE", e))?;
+        Ok(())
+    }
+
+    pub fn granularity(&self) -> Granularity {
+        self.granularity.unwrap_or_default()
+    }
+
+    pub fn tz(&self) -> Result<Tz, String> {
+        timezone(self.timezone.as_deref())
+    }
+
+    /// Returns the events that are aggregated, as an [`EventQuery`].
+    pub fn events(&self) -> EventQuery {
+        EventQuery {
+            product: self.product.clone(),
+            vendor: self.vendor.clone(),
+            version: self.version.clone(),
+            os: self.os.clone(),
+            arch: self.arch.clone(),
+            distribution: self.distribution.clone(),
+            from: self.from,
+            to: self.to,
+            data: self.data.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the coarsest rollup that can answer this query, which is only possible if
+    /// it only looks at the envelope fields that are rolled up and the buckets are in UTC.
+    pub fn rollup(&self) -> Option<Granularity> {
+        let rolled_up = self.vendor.is_none()
+            && self.data.is_empty()
+            && !self
+                .dimensions
+                .iter()
+                .any(|d| matches!(d, Dimension::Data(_)))
+            && self.tz() == Ok(Tz::UTC);
+
+        if !rolled_up {
+            return None;
+        }
+
+        CountsQuery {
+            from: self.from,
+            to: self.to,
+            granularity: Some(self.granularity()),
+            ..Default::default()
+        }
+        .rollup()
+    }
+
+    /// Aggregates the given events, for the backends that can't do it themselves.
+    pub fn aggregate<'a, I: IntoIterator<Item = &'a Event>>(&self, events: I) -> Aggregation {
+        let tz = self.tz().unwrap_or(Tz::UTC);
+        let mut counts = BTreeMap::<(Vec<Option<String>>, DateTime<Utc>), u64>::new();
+        for event in events {
+            let group = self.dimensions.iter().map(|d| d.value(event)).collect();
+            let time = self
+                .granularity()
+                .truncate_in(event.fired_at, &tz)
+                .with_timezone(&Utc);
+
+            *counts.entry((group, time)).or_default() += 1;
+        }
+
+        self.series(
+            counts
+                .into_iter()
+                .map(|((group, time), count)| (group, time, count)),
+        )
+    }
+
+    /// Turns `(group, bucket, count)` rows into one series per group.
+    pub fn series<I>(&self, rows: I) -> Aggregation
+    where
+        I: IntoIterator<Item = (Vec<Option<String>>, DateTime<Utc>, u64)>,
+    {
+        let tz = self.tz().unwrap_or(Tz::UTC);
+        let mut series = BTreeMap::<Vec<Option<String>>, Vec<Point>>::new();
+        for (group, time, count) in rows {
+            series.entry(group).or_default().push(Point {
+                time: time.with_timezone(&tz).fixed_offset(),
+                count,
+            });
+        }
+
+        Aggregation {
+            granularity: self.granularity(),
+            timezone: tz.name().to_string(),
+            group_by: self.dimensions.iter().map(Dimension::name).collect(),
+            series: series
+                .into_iter()
+                .map(|(group, mut points)| {
+                    points.sort_by_key(|p| p.time);
+                    Series {
+                        group: self
+                            .dimensions
+                            .iter()
+                            .map(Dimension::name)
+                            .zip(group)
+                            .collect(),
+                        total: points.iter().map(|p| p.count).sum(),
+                        points,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Point {
+    pub time: DateTime<FixedOffset>,
+    pub count: u64,
+}
+
+/// Represents the events of a single group, bucketed over time.
+#[derive(Serialize, Debug, Clone)]
+pub struct Series {
+    pub group: BTreeMap<String, Option<String>>,
+    pub total: u64,
+    pub points: Vec<Point>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Aggregation {
+    pub granularity: Granularity,
+    pub timezone: String,
+    pub group_by: Vec<String>,
+    pub series: Vec<Series>,
+}
+
+/// Represents a page of events, and the cursor to get the next one.
+#[derive(Serialize, Debug, Clone)]
+pub struct EventPage {
+    pub events: Vec<Event>,
+    pub next_cursor: Option<String>,
+}
+
+fn column_matches(event: &Event, column: &str, value: &str) -> bool {
+    match column {
+        "Product" => event.product == value,
+        "Vendor" => event.vendor == value,
+        "Version" => event.version == value,
+        "OS" => event.os == value,
+        "Arch" => event.arch == value,
+        "Distribution" => event.distribution == value,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use serde_json::json;
+
+    use super::{AggregateQuery, CountsQuery, DataFilter, Granularity};
+
+    #[test]
+    fn picks_the_coarsest_aligned_rollup() {
+        let midnight = Utc.with_ymd_and_hms(2023, 4, 1, 0, 0, 0).unwrap();
+        let noon = Utc.with_ymd_and_hms(2023, 4, 1, 12, 0, 0).unwrap();
+        let query = |granularity, from, to| CountsQuery {
+            granularity: Some(granularity),
+            from,
+            to,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            query(Granularity::Day, Some(midnight), None).rollup(),
+            Some(Granularity::Day)
+        );
+
+        assert_eq!(
+            query(Granularity::Day, Some(noon), None).rollup(),
+            Some(Granularity::Hour)
+        );
+
+        assert_eq!(
+            query(Granularity::Hour, Some(midnight), Some(noon)).rollup(),
+            Some(Granularity::Hour)
+        );
+
+        assert_eq!(query(Granularity::Minute, None, None).rollup(), None);
+        assert_eq!(
+            query(
+                Granularity::Hour,
+                Some(noon + chrono::Duration::minutes(5)),
+                None
+            )
+            .rollup(),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_data_filters() {
+        let filters = DataFilter::from_pairs(&[
+            ("product".into(), "charted-server".into()),
+            ("data.cluster.nodes".into(), "3".into()),
+        ])
+        .unwrap();
+
+        assert_eq!(filters.len(), 1);
+        assert_eq!(filters[0].path, vec!["cluster", "nodes"]);
+        assert!(filters[0].matches(&json!({ "cluster": { "nodes": 3 } })));
+        assert!(!filters[0].matches(&json!({ "cluster": { "nodes": "4" } })));
+
+        assert!(DataFilter::from_pairs(&[("data.a..b".into(), "".into())]).is_err());
+        assert!(DataFilter::from_pairs(&[("data.a'b".into(), "".into())]).is_err());
+    }
+
+    #[test]
+    fn truncates_in_timezones() {
+        let time = Utc.with_ymd_and_hms(2023, 4, 2, 1, 30, 0).unwrap();
+        assert_eq!(
+            Granularity::Week.truncate(time),
+            Utc.with_ymd_and_hms(2023, 3, 27, 0, 0, 0).unwrap()
+        );
+
+        // 2023-04-02T01:30Z is 2023-04-02T03:30 in Berlin
+        assert_eq!(
+            Granularity::Day
+                .truncate_in(time, &chrono_tz::Europe::Berlin)
+                .with_timezone(&Utc),
+            Utc.with_ymd_and_hms(2023, 4, 1, 22, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn validates_aggregate_queries() {
+        let mut query = AggregateQuery {
+            group_by: Some("product,data.cluster.nodes".into()),
+            timezone: Some("Europe/Berlin".into()),
+            ..Default::default()
+        };
+
+        assert!(query.validate().is_ok());
+        assert_eq!(query.dimensions.len(), 2);
+        assert_eq!(query.rollup(), None);
+
+        query.group_by = Some("product,owo".into());
+        assert_eq!(query.validate().unwrap_err().0, "INVALID_GROUP_BY");
+
+        query.group_by = None;
+        query.timezone = Some("Mars/Olympus_Mons".into());
+        assert_eq!(query.validate().unwrap_err().0, "INVALID_TIMEZONE");
+    }
+}
@@ -0,0 +1,144 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::config::Sources;
+
+/// Telemetry server for Noelware's products. Runs the HTTP service if no
+/// subcommand was given.
+#[derive(Debug, Parser)]
+#[command(name = "telemetry-server", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Configuration file to read, defaults to `TELEMETRY_CONFIG_PATH` or `./config.toml`.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Host to listen on, overrides `config.host`.
+    #[arg(long, global = true)]
+    pub host: Option<String>,
+
+    /// Port to listen on, overrides `config.port`.
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+
+    /// Level to log at, overrides `config.logging.level`.
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Overrides any setting, i.e, `--set clickhouse.port=9440`. Can be repeated.
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+}
+
+impl Cli {
+    /// Returns where the configuration is read from, with the flags as overrides.
+    pub fn sources(&self) -> Sources {
+        let mut overrides = vec![];
+        if let Some(host) = &self.host {
+            overrides.push(format!("host={}", host));
+        }
+
+        if let Some(port) = self.port {
+            overrides.push(format!("port={}", port));
+        }
+
+        if let Some(level) = &self.log_level {
+            overrides.push(format!("logging.level={}", level));
+        }
+
+        overrides.extend(self.overrides.iter().cloned());
+        Sources {
+            path: self.config.clone(),
+            overrides,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Runs the HTTP service, which is also what happens if no subcommand was given.
+    Serve,
+
+    /// Checks or prints the configuration.
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    /// Checks if the server running on this machine is ready, and exits with a non-zero
+    /// code if it isn't. Used by the Docker image's `HEALTHCHECK`, which has no curl.
+    Healthcheck {
+        /// Endpoint to check.
+        #[arg(long, default_value = "/readyz")]
+        path: String,
+
+        /// How many seconds to wait for the server to respond.
+        #[arg(long, default_value_t = 5)]
+        timeout: u64,
+    },
+
+    /// Prints the version, commit and build date of this binary.
+    Version,
+
+    /// Re-ingests the request bodies that the server failed to store.
+    Replay {
+        /// Only report what would happen, without inserting or moving anything.
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Directory to read the failed bodies from, defaults to `config.dead_letters.directory`.
+        #[arg(long)]
+        directory: Option<PathBuf>,
+    },
+
+    /// Manages the schema migrations of the configured storage backend.
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MigrateAction {
+    /// Applies every pending migration, in order.
+    Up {
+        /// Only list the migrations that would be applied.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Lists every migration and when it was applied.
+    Status,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigAction {
+    /// Resolves the configuration and reports every problem with it.
+    Check,
+
+    /// Prints the configuration as TOML, with its secrets redacted.
+    Print {
+        /// Prints every setting with the value it resolved to, defaults included, rather
+        /// than only the ones that were set.
+        #[arg(long)]
+        effective: bool,
+    },
+}
@@ -13,61 +13,228 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
 
-use clickhouse_rs::{types::Complex, Block, Pool};
-use once_cell::sync::OnceCell;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use clickhouse_rs::{
+    types::{ColumnType, Complex, Row},
+    Block, ClientHandle, Pool,
+};
+use clickhouse_tz::{Tz, UTC};
 
-use crate::config::ClickHouseConfig;
+use crate::{
+    adoption::{Adoption, AdoptionQuery},
+    analytics::{
+        AggregateQuery, Aggregation, CountBucket, Counts, CountsQuery, Dimension, EventQuery,
+        Granularity,
+    },
+    cohorts::{CohortMatrix, CohortQuery},
+    config::{ClickHouseConfig, RetentionConfig},
+    dead_letters::{BodyEncoding, DeadLetter, DeadLetterQuery, RejectionReason},
+    funnels::{Funnel, FunnelQuery},
+    metrics::METRICS,
+    migrations::{self, AppliedMigration, Migration},
+    percentiles::{PercentileQuery, Percentiles, Summary},
+    storage::{Event, EventStore, ProductStats, Result},
+};
 
-static DATABASE_CALLS: OnceCell<AtomicUsize> = OnceCell::new();
+static DATABASE_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+const DATABASE: &str = "CREATE DATABASE IF NOT EXISTS telemetry";
+
+const SCHEMA_MIGRATIONS: &str = r#"CREATE TABLE IF NOT EXISTS telemetry.schema_migrations(
+    Version UInt32,
+    Name String,
+    AppliedAt DateTime('UTC')
+) ENGINE=MergeTree() ORDER BY Version"#;
+
+/// Represents a rollup table that counts events by their envelope fields, which is
+/// kept in sync with `telemetry.events` by a materialized view (see the
+/// `create_rollups` migration).
+struct Rollup {
+    granularity: Granularity,
+    table: &'static str,
+}
+
+const ROLLUPS: [Rollup; 2] = [
+    Rollup {
+        granularity: Granularity::Hour,
+        table: "events_hourly",
+    },
+    Rollup {
+        granularity: Granularity::Day,
+        table: "events_daily",
+    },
+];
+
+impl Rollup {
+    fn find(granularity: Granularity) -> Option<&'static Rollup> {
+        ROLLUPS.iter().find(|r| r.granularity == granularity)
+    }
+}
 
 /// Represents the main ClickHouse connection with methods to query
 /// objects with a simple `.sql("<query>", move |result| {})` function.
 #[derive(Debug, Clone)]
 pub struct ClickHouse {
-    #[allow(dead_code)]
-    pool: Pool,
+    // taken out (and dropped) once the server stopped, so the idle connections are closed
+    // and no new ones are opened.
+    pool: Arc<Mutex<Option<Pool>>>,
+
+    // the same connection settings, but with the `default` database, since ClickHouse
+    // refuses connections to the `telemetry` database before it is created.
+    bootstrap: ClickHouseConfig,
+
+    // set once the `telemetry` database was created, so it's only done once.
+    database: Arc<tokio::sync::OnceCell<()>>,
+
+    // the TTL clause that was last applied on `telemetry.events`, so we don't
+    // rewrite the table's parts every time the retention is re-applied.
+    ttl: Arc<Mutex<Option<String>>>,
+
+    // same as `ttl`, but for `telemetry.dead_letters`.
+    dead_letter_ttl: Arc<Mutex<Option<String>>>,
 }
 
 impl ClickHouse {
     pub fn new(config: &ClickHouseConfig) -> ClickHouse {
-        let url = config.to_string();
+        let url = config.url();
         let pool = Pool::new(url);
 
-        DATABASE_CALLS
-            .set(AtomicUsize::new(0))
-            .expect("Unable to create db calls constant.");
+        ClickHouse {
+            pool: Arc::new(Mutex::new(Some(pool))),
+            bootstrap: ClickHouseConfig {
+                database: Some("default".into()),
+                ..config.clone()
+            },
+            database: Arc::new(tokio::sync::OnceCell::new()),
+            ttl: Arc::new(Mutex::new(None)),
+            dead_letter_ttl: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Creates the `telemetry` database the first time it is called, through a short-lived
+    /// connection to the `default` database.
+    async fn create_database(&self) -> Result<()> {
+        self.database
+            .get_or_try_init(|| async {
+                let pool = Pool::new(self.bootstrap.url());
+                let mut handle = pool.get_handle().await?;
+                handle.execute(DATABASE).await?;
+                Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+            })
+            .await?;
 
-        ClickHouse { pool }
+        Ok(())
     }
 
-    pub fn calls() -> usize {
-        DATABASE_CALLS.get().unwrap().load(Ordering::SeqCst)
+    /// Checks if the given table (`database.table`) or column (`database.table.column`)
+    /// exists.
+    async fn exists(&self, name: &str) -> Result<bool> {
+        let sql = match name.splitn(3, '.').collect::<Vec<_>>()[..] {
+            [database, table] => format!(
+                "SELECT count() FROM system.tables WHERE database = {} AND name = {}",
+                quote(database),
+                quote(table)
+            ),
+
+            [database, table, column] => format!(
+                "SELECT count() FROM system.columns WHERE database = {} AND table = {} AND name = {}",
+                quote(database),
+                quote(table),
+                quote(column)
+            ),
+
+            _ => return Err(format!("expected `database.table[.column]`, got {}", name).into()),
+        };
+
+        let count = self
+            .query(sql, |block| block.get::<u64, _>(0, 0).unwrap_or(0))
+            .await?;
+
+        Ok(count > 0)
     }
 
-    pub async fn ping(self) -> Result<(), Box<dyn std::error::Error>> {
-        debug!("grabbing connection...");
-        let pool = self.pool.clone();
-        let mut handle = pool.get_handle().await?;
+    /// Sets the TTL of the given table, or removes it if the clause is empty.
+    async fn set_ttl(&self, table: &str, clause: &str) -> Result<()> {
+        if clause.is_empty() {
+            // ClickHouse errors if we remove a TTL from a table that doesn't have one.
+            let has_ttl = self
+                .query(
+                    format!(
+                        "SELECT engine_full FROM system.tables WHERE database = 'telemetry' AND name = {}",
+                        quote(table)
+                    ),
+                    |block| -> Result<bool> {
+                        Ok(block
+                            .rows()
+                            .next()
+                            .map(|row| row.get::<String, _>("engine_full"))
+                            .transpose()?
+                            .is_some_and(|engine| engine.contains(" TTL ")))
+                    },
+                )
+                .await??;
 
-        DATABASE_CALLS.get().unwrap().fetch_add(1, Ordering::SeqCst);
-        handle.ping().await?;
+            if has_ttl {
+                self.execute(format!("ALTER TABLE telemetry.{} REMOVE TTL", table))
+                    .await?;
+            }
+        } else {
+            info!("applying retention on telemetry.{}: TTL {}", table, clause);
+            self.execute(format!(
+                "ALTER TABLE telemetry.{} MODIFY TTL {}",
+                table, clause
+            ))
+            .await?;
+        }
 
         Ok(())
     }
 
-    pub async fn query<S, F, U>(&self, sql: S, func: F) -> Result<U, Box<dyn std::error::Error>>
+    pub fn calls() -> usize {
+        DATABASE_CALLS.load(Ordering::SeqCst)
+    }
+
+    /// Grabs a connection from the pool, and records how long it took.
+    async fn handle(&self) -> Result<ClientHandle> {
+        let pool = self
+            .pool
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or("the ClickHouse connection pool was closed")?;
+
+        let started = Instant::now();
+        let handle = pool.get_handle().await;
+        METRICS.observe_pool_wait("clickhouse", handle.is_ok(), started.elapsed());
+
+        Ok(handle?)
+    }
+
+    #[tracing::instrument(
+        name = "clickhouse.query",
+        skip_all,
+        fields(db.system = "clickhouse", db.statement = %sql.as_ref())
+    )]
+    pub async fn query<S, F, U>(&self, sql: S, func: F) -> Result<U>
     where
         S: Into<String> + AsRef<str>,
         F: Fn(Block<Complex>) -> U,
     {
         debug!("grabbing connection...");
-        let pool = self.pool.clone();
-        let mut handle = pool.get_handle().await?;
+        let mut handle = self.handle().await?;
 
         debug!("grabbed connection successfully!");
-        DATABASE_CALLS.get().unwrap().fetch_add(1, Ordering::SeqCst);
+        DATABASE_CALLS.fetch_add(1, Ordering::SeqCst);
 
         let block = handle.query(sql).fetch_all().await?;
         let result = func(block);
@@ -75,18 +242,957 @@ impl ClickHouse {
         Ok(result)
     }
 
-    pub async fn insert<S>(&self, table: S, block: Block) -> Result<(), Box<dyn std::error::Error>>
+    #[tracing::instrument(
+        name = "clickhouse.insert",
+        skip_all,
+        fields(db.system = "clickhouse", db.sql.table = %table.as_ref(), db.rows = block.row_count())
+    )]
+    pub async fn insert<S>(&self, table: S, block: Block) -> Result<()>
     where
         S: Into<String> + AsRef<str>,
     {
         debug!("grabbing connection...");
-        let pool = self.pool.clone();
-        let mut handle = pool.get_handle().await?;
+        let mut handle = self.handle().await?;
 
         debug!("grabbed connection successfully!");
-        DATABASE_CALLS.get().unwrap().fetch_add(1, Ordering::SeqCst);
+        DATABASE_CALLS.fetch_add(1, Ordering::SeqCst);
 
         handle.insert(table, block).await?;
         Ok(())
     }
+
+    #[tracing::instrument(
+        name = "clickhouse.execute",
+        skip_all,
+        fields(db.system = "clickhouse", db.statement = %sql.as_ref())
+    )]
+    pub async fn execute<S>(&self, sql: S) -> Result<()>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        debug!("grabbing connection...");
+        let mut handle = self.handle().await?;
+
+        debug!("grabbed connection successfully!");
+        DATABASE_CALLS.fetch_add(1, Ordering::SeqCst);
+
+        handle.execute(sql).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EventStore for ClickHouse {
+    fn name(&self) -> &'static str {
+        "clickhouse"
+    }
+
+    fn calls(&self) -> usize {
+        ClickHouse::calls()
+    }
+
+    async fn ping(&self) -> Result<()> {
+        debug!("grabbing connection...");
+        let mut handle = self.handle().await?;
+
+        DATABASE_CALLS.fetch_add(1, Ordering::SeqCst);
+        handle.ping().await?;
+
+        Ok(())
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        migrations::CLICKHOUSE
+    }
+
+    async fn applied_migrations(&self) -> Result<Vec<AppliedMigration>> {
+        self.create_database().await?;
+        self.execute(SCHEMA_MIGRATIONS).await?;
+        self.query(
+            "SELECT Version, Name, AppliedAt FROM telemetry.schema_migrations ORDER BY Version",
+            |block| -> Result<Vec<AppliedMigration>> {
+                let mut applied = vec![];
+                for row in block.rows() {
+                    let applied_at: DateTime<Tz> = row.get("AppliedAt")?;
+                    applied.push(AppliedMigration {
+                        version: row.get("Version")?,
+                        name: row.get("Name")?,
+                        applied_at: applied_at.with_timezone(&Utc),
+                    });
+                }
+
+                Ok(applied)
+            },
+        )
+        .await?
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        // ClickHouse has no transactional DDL, so migrations must be written in a way
+        // that can safely be re-run if one of the statements fails.
+        for statement in migration.statements() {
+            if let Some(name) = statement.unless_exists {
+                if self.exists(name).await? {
+                    debug!("skipping statement since {} exists", name);
+                    continue;
+                }
+            }
+
+            self.execute(statement.sql).await?;
+        }
+
+        let block = Block::new()
+            .column("Version", vec![migration.version])
+            .column("Name", vec![migration.name.to_string()])
+            .column("AppliedAt", vec![Utc::now().with_timezone(&UTC)]);
+
+        self.insert("schema_migrations", block).await
+    }
+
+    async fn insert_events(&self, events: Vec<Event>) -> Result<()> {
+        let mut ids = Vec::with_capacity(events.len());
+        let mut products = Vec::with_capacity(events.len());
+        let mut vendors = Vec::with_capacity(events.len());
+        let mut installation_ids = Vec::with_capacity(events.len());
+        let mut distributions = Vec::with_capacity(events.len());
+        let mut versions = Vec::with_capacity(events.len());
+        let mut arches = Vec::with_capacity(events.len());
+        let mut oses = Vec::with_capacity(events.len());
+        let mut fired_at = Vec::with_capacity(events.len());
+        let mut data = Vec::with_capacity(events.len());
+
+        for event in events {
+            ids.push(event.id);
+            products.push(event.product);
+            vendors.push(event.vendor);
+            installation_ids.push(event.installation_id.unwrap_or_default());
+            distributions.push(event.distribution);
+            versions.push(event.version);
+            arches.push(event.arch);
+            oses.push(event.os);
+            fired_at.push(event.fired_at.with_timezone(&UTC));
+            data.push(event.data.to_string());
+        }
+
+        let block = Block::new()
+            .column("ID", ids)
+            .column("Product", products)
+            .column("Vendor", vendors)
+            .column("InstallationID", installation_ids)
+            .column("Distribution", distributions)
+            .column("Version", versions)
+            .column("Arch", arches)
+            .column("OS", oses)
+            .column("FiredAt", fired_at)
+            .column("Data", data);
+
+        self.insert("events", block).await
+    }
+
+    async fn count_events(&self) -> Result<u64> {
+        self.query("SELECT COUNT(*) FROM telemetry.events", |block| {
+            block.get::<u64, _>(0, 0).unwrap_or(0)
+        })
+        .await
+    }
+
+    async fn events(&self, query: &EventQuery) -> Result<Vec<Event>> {
+        let sql = format!(
+            "SELECT ID, Product, Vendor, InstallationID, Distribution, Version, Arch, OS, FiredAt, Data FROM telemetry.events{} ORDER BY ID DESC LIMIT {}",
+            where_clause(&conditions(query, "FiredAt")),
+            query.limit()
+        );
+
+        self.query(sql, |block| -> Result<Vec<Event>> {
+            let mut events = vec![];
+            for row in block.rows() {
+                let fired_at: DateTime<Tz> = row.get("FiredAt")?;
+                let data: String = row.get("Data")?;
+                let installation_id: String = row.get("InstallationID")?;
+
+                events.push(Event {
+                    id: row.get("ID")?,
+                    product: row.get("Product")?,
+                    vendor: row.get("Vendor")?,
+                    installation_id: Some(installation_id).filter(|id| !id.is_empty()),
+                    distribution: row.get("Distribution")?,
+                    version: row.get("Version")?,
+                    arch: row.get("Arch")?,
+                    os: row.get("OS")?,
+                    data: serde_json::from_str(&data).unwrap_or(serde_json::Value::String(data)),
+                    fired_at: fired_at.with_timezone(&Utc),
+                });
+            }
+
+            Ok(events)
+        })
+        .await?
+    }
+
+    async fn product_stats(&self) -> Result<Vec<ProductStats>> {
+        self.query(
+            "SELECT Product, count() AS Events, min(FiredAt) AS OldestEvent FROM telemetry.events GROUP BY Product ORDER BY Product",
+            |block| -> Result<Vec<ProductStats>> {
+                let mut stats = vec![];
+                for row in block.rows() {
+                    let oldest_event: DateTime<Tz> = row.get("OldestEvent")?;
+                    stats.push(ProductStats {
+                        product: row.get("Product")?,
+                        events: row.get("Events")?,
+                        oldest_event: Some(oldest_event.with_timezone(&Utc)),
+                    });
+                }
+
+                Ok(stats)
+            },
+        )
+        .await?
+    }
+
+    async fn apply_retention(&self, retention: &RetentionConfig) -> Result<()> {
+        let clause = ttl_clause(retention, "FiredAt");
+        if *self.ttl.lock().unwrap() == Some(clause.clone()) {
+            return Ok(());
+        }
+
+        // The rollups get the same TTL, so they don't count events that were already
+        // deleted. A bucket expires once its start is past the retention.
+        self.set_ttl("events", &clause).await?;
+        for rollup in ROLLUPS.iter() {
+            self.set_ttl(rollup.table, &ttl_clause(retention, "Time"))
+                .await?;
+        }
+
+        *self.ttl.lock().unwrap() = Some(clause);
+        Ok(())
+    }
+
+    async fn apply_dead_letter_retention(&self, days: u32) -> Result<()> {
+        let clause = format!("ReceivedAt + INTERVAL {} DAY", days);
+        if *self.dead_letter_ttl.lock().unwrap() == Some(clause.clone()) {
+            return Ok(());
+        }
+
+        self.set_ttl("dead_letters", &clause).await?;
+        *self.dead_letter_ttl.lock().unwrap() = Some(clause);
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<()> {
+        // clickhouse-rs closes the idle connections once the last clone of the pool is
+        // dropped, which is ours since in-flight events were drained by now.
+        let pool = self.pool.lock().unwrap().take();
+        if pool.is_some() {
+            drop(pool);
+            debug!("closed the ClickHouse connection pool");
+        }
+
+        Ok(())
+    }
+
+    async fn missing_tables(&self) -> Result<Vec<String>> {
+        let tables = self
+            .query(
+                "SELECT name FROM system.tables WHERE database = 'telemetry'",
+                |block| -> Result<Vec<String>> {
+                    let mut tables = vec![];
+                    for row in block.rows() {
+                        tables.push(row.get("name")?);
+                    }
+
+                    Ok(tables)
+                },
+            )
+            .await??;
+
+        Ok(["events", "dead_letters", "schema_migrations"]
+            .into_iter()
+            .chain(ROLLUPS.iter().map(|rollup| rollup.table))
+            .filter(|table| !tables.iter().any(|t| t == table))
+            .map(|table| format!("telemetry.{}", table))
+            .collect())
+    }
+
+    async fn event_counts(&self, query: &CountsQuery) -> Result<Counts> {
+        let granularity = query.granularity();
+        let (source, time, count) = match query.rollup().and_then(Rollup::find) {
+            Some(rollup) => (rollup.table, "Time", "sum(Events)"),
+            None => ("events", "FiredAt", "count()"),
+        };
+
+        let mut conditions = query
+            .filters()
+            .into_iter()
+            .map(|(column, value)| format!("{} = {}", column, quote(value)))
+            .collect::<Vec<_>>();
+
+        if let Some(from) = query.from {
+            conditions.push(format!("{} >= {}", time, datetime(from)));
+        }
+
+        if let Some(to) = query.to {
+            conditions.push(format!("{} < {}", time, datetime(to)));
+        }
+
+        let sql = format!(
+            "SELECT {} AS Bucket, {} AS Events FROM telemetry.{}{} GROUP BY Bucket ORDER BY Bucket",
+            bucket(granularity, time, "UTC"),
+            count,
+            source,
+            if conditions.is_empty() {
+                String::new()
+            } else {
+                format!(" WHERE {}", conditions.join(" AND "))
+            }
+        );
+
+        let buckets = self
+            .query(sql, |block| -> Result<Vec<CountBucket>> {
+                let mut buckets = vec![];
+                for row in block.rows() {
+                    let time: DateTime<Tz> = row.get("Bucket")?;
+                    buckets.push(CountBucket {
+                        time: time.with_timezone(&Utc),
+                        count: row.get("Events")?,
+                    });
+                }
+
+                Ok(buckets)
+            })
+            .await??;
+
+        Ok(Counts {
+            granularity,
+            source,
+            buckets,
+        })
+    }
+
+    async fn aggregate(&self, query: &AggregateQuery) -> Result<Aggregation> {
+        let tz = query.tz()?;
+        let (source, time, count) = match query.rollup().and_then(Rollup::find) {
+            Some(rollup) => (rollup.table, "Time", "sum(Events)"),
+            None => ("events", "FiredAt", "count()"),
+        };
+
+        let mut columns = dimensions(&query.dimensions);
+        let groups = groups(&query.dimensions)
+            .into_iter()
+            .chain(["Bucket".to_string()])
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        columns.push(format!(
+            "{} AS Bucket",
+            bucket(query.granularity(), time, tz.name())
+        ));
+        columns.push(format!("{} AS Events", count));
+
+        let sql = format!(
+            "SELECT {} FROM telemetry.{}{} GROUP BY {} ORDER BY Bucket",
+            columns.join(", "),
+            source,
+            where_clause(&conditions(&query.events(), time)),
+            groups
+        );
+
+        let dimensions = query.dimensions.len();
+        let rows = self
+            .query(sql, |block| -> Result<Vec<_>> {
+                let mut rows = vec![];
+                for row in block.rows() {
+                    let group = read_group(&row, dimensions)?;
+                    let time: DateTime<Tz> = row.get("Bucket")?;
+                    rows.push((group, time.with_timezone(&Utc), row.get("Events")?));
+                }
+
+                Ok(rows)
+            })
+            .await??;
+
+        Ok(query.series(rows))
+    }
+
+    async fn version_adoption(&self, query: &AdoptionQuery) -> Result<Adoption> {
+        let tz = query.tz()?;
+        let mut conditions = conditions(&query.events(), "FiredAt");
+        conditions.push("InstallationID != ''".into());
+
+        // the version an installation was on in each bucket is the last one it reported.
+        let installs = self
+            .query(
+                format!(
+                    "SELECT Bucket, Version, count() AS Installations FROM (SELECT {} AS Bucket, InstallationID, argMax(Version, (FiredAt, ID)) AS Version FROM telemetry.events{} GROUP BY Bucket, InstallationID) GROUP BY Bucket, Version ORDER BY Bucket, Version",
+                    bucket(query.granularity(), "FiredAt", tz.name()),
+                    where_clause(&conditions)
+                ),
+                |block| -> Result<Vec<_>> {
+                    let mut rows = vec![];
+                    for row in block.rows() {
+                        let time: DateTime<Tz> = row.get("Bucket")?;
+                        rows.push((
+                            time.with_timezone(&Utc),
+                            row.get("Version")?,
+                            row.get("Installations")?,
+                        ));
+                    }
+
+                    Ok(rows)
+                },
+            )
+            .await??;
+
+        // an installation is only counted once for each transition, even if it went back
+        // and forth between the same versions.
+        let transitions = self
+            .query(
+                format!(
+                    "SELECT Transition.1 AS FromVersion, Transition.2 AS ToVersion, count() AS Installations FROM (SELECT arrayMap(e -> e.3, arraySort(groupArray((FiredAt, ID, Version)))) AS Versions FROM telemetry.events{} GROUP BY InstallationID) ARRAY JOIN arrayDistinct(arrayFilter(u -> u.1 != u.2, arrayZip(arrayPopBack(Versions), arrayPopFront(Versions)))) AS Transition GROUP BY FromVersion, ToVersion",
+                    where_clause(&conditions)
+                ),
+                |block| -> Result<Vec<_>> {
+                    let mut rows = vec![];
+                    for row in block.rows() {
+                        rows.push((
+                            row.get("FromVersion")?,
+                            row.get("ToVersion")?,
+                            row.get("Installations")?,
+                        ));
+                    }
+
+                    Ok(rows)
+                },
+            )
+            .await??;
+
+        Ok(query.adoption(installs, transitions))
+    }
+
+    async fn cohorts(&self, query: &CohortQuery) -> Result<CohortMatrix> {
+        let tz = query.tz()?;
+        let mut installations = conditions(&query.events(), "FiredAt");
+        installations.push("InstallationID != ''".into());
+
+        let mut range = vec![];
+        if let Some(from) = query.from {
+            range.push(format!("FirstSeen >= {}", datetime(from)));
+        }
+
+        if let Some(to) = query.to {
+            range.push(format!("FirstSeen < {}", datetime(to)));
+        }
+
+        let cohorts = format!(
+            "SELECT InstallationID, {} AS Cohort FROM (SELECT InstallationID, min(FiredAt) AS FirstSeen FROM telemetry.events{} GROUP BY InstallationID){}",
+            bucket(query.granularity(), "FirstSeen", tz.name()),
+            where_clause(&installations),
+            where_clause(&range)
+        );
+
+        let sizes = self
+            .query(
+                format!(
+                    "SELECT Cohort, count() AS Installations FROM ({}) GROUP BY Cohort ORDER BY Cohort",
+                    cohorts
+                ),
+                |block| -> Result<Vec<_>> {
+                    let mut rows = vec![];
+                    for row in block.rows() {
+                        let cohort: DateTime<Tz> = row.get("Cohort")?;
+                        rows.push((cohort.with_timezone(&Utc), row.get("Installations")?));
+                    }
+
+                    Ok(rows)
+                },
+            )
+            .await??;
+
+        let activity = conditions(
+            &EventQuery {
+                data: query.data.clone(),
+                ..query.events()
+            },
+            "FiredAt",
+        )
+        .into_iter()
+        .chain(["InstallationID != ''".to_string()])
+        .collect::<Vec<_>>();
+
+        // buckets in a timezone aren't always the same length when the clocks change, so
+        // periods are rounded like `CohortQuery::period` does.
+        let retained = self
+            .query(
+                format!(
+                    "SELECT Cohort, toInt64(round(dateDiff('second', Cohort, Active) / {})) AS Period, count() AS Installations FROM ({}) AS cohorts INNER JOIN (SELECT InstallationID, {} AS Active FROM telemetry.events{} GROUP BY InstallationID, Active HAVING count() >= {}) AS activity USING (InstallationID) WHERE Period >= 0 AND Period <= {} GROUP BY Cohort, Period",
+                    query.granularity().seconds(),
+                    cohorts,
+                    bucket(query.granularity(), "FiredAt", tz.name()),
+                    where_clause(&activity),
+                    query.min_events(),
+                    query.periods()
+                ),
+                |block| -> Result<Vec<_>> {
+                    let mut rows = vec![];
+                    for row in block.rows() {
+                        let cohort: DateTime<Tz> = row.get("Cohort")?;
+                        rows.push((
+                            cohort.with_timezone(&Utc),
+                            row.get("Period")?,
+                            row.get("Installations")?,
+                        ));
+                    }
+
+                    Ok(rows)
+                },
+            )
+            .await??;
+
+        Ok(query.matrix(sizes, retained, Utc::now()))
+    }
+
+    async fn funnel(&self, query: &FunnelQuery) -> Result<Funnel> {
+        let mut conditions = conditions(&query.events(), "FiredAt");
+        conditions.push("InstallationID != ''".into());
+
+        let steps = query
+            .predicates
+            .iter()
+            .map(|filters| {
+                if filters.is_empty() {
+                    return "1".to_string();
+                }
+
+                let filters = filters
+                    .iter()
+                    .map(|filter| format!("{} = {}", json_text(&filter.path), quote(&filter.value)))
+                    .collect::<Vec<_>>();
+
+                format!("({})", filters.join(" AND "))
+            })
+            .collect::<Vec<_>>();
+
+        // installations are broken down by the version they entered the funnel with.
+        let rows = self
+            .query(
+                format!(
+                    "SELECT Version, Level, count() AS Installations FROM (SELECT argMinIf(Version, (FiredAt, ID), {}) AS Version, windowFunnel({})(toDateTime(FiredAt), {}) AS Level FROM telemetry.events{} GROUP BY InstallationID) WHERE Level > 0 GROUP BY Version, Level",
+                    steps[0],
+                    query.window(),
+                    steps.join(", "),
+                    where_clause(&conditions)
+                ),
+                |block| -> Result<Vec<_>> {
+                    let mut rows = vec![];
+                    for row in block.rows() {
+                        let level: u8 = row.get("Level")?;
+                        rows.push((row.get("Version")?, level as usize, row.get("Installations")?));
+                    }
+
+                    Ok(rows)
+                },
+            )
+            .await??;
+
+        Ok(query.funnel(rows))
+    }
+
+    async fn percentiles(&self, query: &PercentileQuery) -> Result<Percentiles> {
+        let path = query
+            .keys
+            .iter()
+            .map(|key| quote(key))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let mut conditions = conditions(&query.events(), "FiredAt");
+        conditions.push(format!(
+            "JSONType(Data, {}) IN ('Int64', 'UInt64', 'Double')",
+            path
+        ));
+
+        let samples = format!(
+            "SELECT {} JSONExtractFloat(Data, {}) AS Value FROM telemetry.events{}",
+            dimensions(&query.dimensions)
+                .into_iter()
+                .map(|column| column + ",")
+                .collect::<Vec<_>>()
+                .join(" "),
+            path,
+            where_clause(&conditions)
+        );
+
+        let groups = groups(&query.dimensions);
+        let group_by = if groups.is_empty() {
+            String::new()
+        } else {
+            format!(" GROUP BY {}", groups.join(", "))
+        };
+
+        let columns = |rest: &str| {
+            groups
+                .iter()
+                .map(String::as_str)
+                .chain([rest])
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        // ClickHouse's `quantiles` is approximate, which is good enough for spotting regressions.
+        let dimensions = query.dimensions.len();
+        let summaries = self
+            .query(
+                format!(
+                    "SELECT {} FROM ({}){}",
+                    columns("count() AS Samples, avg(Value) AS Mean, min(Value) AS Min, max(Value) AS Max, quantiles(0.5, 0.9, 0.99)(Value) AS Quantiles"),
+                    samples,
+                    group_by
+                ),
+                |block| -> Result<BTreeMap<_, _>> {
+                    let mut summaries = BTreeMap::new();
+                    for row in block.rows() {
+                        let samples: u64 = row.get("Samples")?;
+                        if samples == 0 {
+                            continue;
+                        }
+
+                        let quantiles: Vec<f64> = row.get("Quantiles")?;
+                        summaries.insert(
+                            read_group(&row, dimensions)?,
+                            Summary {
+                                samples,
+                                mean: row.get("Mean")?,
+                                min: row.get("Min")?,
+                                max: row.get("Max")?,
+                                p50: quantiles[0],
+                                p90: quantiles[1],
+                                p99: quantiles[2],
+                            },
+                        );
+                    }
+
+                    Ok(summaries)
+                },
+            )
+            .await??;
+
+        // histogram buckets are evenly spread between each group's minimum and maximum.
+        let buckets = query.buckets();
+        let ranges = format!(
+            "SELECT {} FROM ({}){}",
+            columns("min(Value) AS Lo, max(Value) AS Hi"),
+            samples,
+            group_by
+        );
+
+        let join = if groups.is_empty() {
+            format!("CROSS JOIN ({}) AS ranges", ranges)
+        } else {
+            format!(
+                "INNER JOIN ({}) AS ranges USING ({})",
+                ranges,
+                groups.join(", ")
+            )
+        };
+
+        let histograms = self
+            .query(
+                format!(
+                    "SELECT {} FROM ({}) AS samples {}{}",
+                    columns(&format!("if(Hi > Lo, least(toUInt64(floor((Value - Lo) / (Hi - Lo) * {buckets})), {}), 0) AS Bucket, count() AS Samples", buckets - 1)),
+                    samples,
+                    join,
+                    if groups.is_empty() {
+                        " GROUP BY Bucket".to_string()
+                    } else {
+                        format!("{}, Bucket", group_by)
+                    }
+                ),
+                |block| -> Result<BTreeMap<_, _>> {
+                    let mut histograms = BTreeMap::<_, Vec<u64>>::new();
+                    for row in block.rows() {
+                        let bucket: u64 = row.get("Bucket")?;
+                        histograms
+                            .entry(read_group(&row, dimensions)?)
+                            .or_insert_with(|| vec![0; buckets])[bucket as usize] +=
+                            row.get::<u64, _>("Samples")?;
+                    }
+
+                    Ok(histograms)
+                },
+            )
+            .await??;
+
+        Ok(Percentiles {
+            path: query.path.clone(),
+            group_by: query.dimensions.iter().map(Dimension::name).collect(),
+            groups: summaries
+                .into_iter()
+                .map(|(group, summary)| {
+                    let histogram = histograms
+                        .get(&group)
+                        .cloned()
+                        .unwrap_or_else(|| vec![0; buckets]);
+
+                    query.group(group, summary, histogram)
+                })
+                .collect(),
+        })
+    }
+
+    async fn insert_dead_letter(&self, letter: &DeadLetter) -> Result<()> {
+        let block = Block::new()
+            .column("ID", vec![letter.id])
+            .column("ReceivedAt", vec![letter.received_at.with_timezone(&UTC)])
+            .column("Reason", vec![letter.reason.code().to_string()])
+            .column("Product", vec![letter.product.clone()])
+            .column("Message", vec![letter.message.clone()])
+            .column("Body", vec![letter.body.clone()])
+            .column("Encoding", vec![letter.encoding.code().to_string()]);
+
+        self.insert("dead_letters", block).await
+    }
+
+    async fn dead_letters(&self, query: &DeadLetterQuery) -> Result<Vec<DeadLetter>> {
+        let mut conditions = vec![];
+        if let Some(reason) = &query.reason {
+            conditions.push(format!("Reason = {}", quote(reason)));
+        }
+
+        if let Some(product) = &query.product {
+            conditions.push(format!("Product = {}", quote(product)));
+        }
+
+        let sql = format!(
+            "SELECT ID, ReceivedAt, Reason, Product, Message, Body, Encoding FROM telemetry.dead_letters{} ORDER BY ID DESC LIMIT {}",
+            if conditions.is_empty() {
+                String::new()
+            } else {
+                format!(" WHERE {}", conditions.join(" AND "))
+            },
+            query.limit()
+        );
+
+        self.query(sql, rows_to_letters).await?
+    }
+
+    async fn dead_letter(&self, id: u64) -> Result<Option<DeadLetter>> {
+        let sql = format!(
+            "SELECT ID, ReceivedAt, Reason, Product, Message, Body, Encoding FROM telemetry.dead_letters WHERE ID = {} LIMIT 1",
+            id
+        );
+
+        let letters = self.query(sql, rows_to_letters).await??;
+        Ok(letters.into_iter().next())
+    }
+}
+
+fn rows_to_letters(block: Block<Complex>) -> Result<Vec<DeadLetter>> {
+    let mut letters = vec![];
+    for row in block.rows() {
+        let reason: String = row.get("Reason")?;
+        let encoding: String = row.get("Encoding")?;
+        let received_at: DateTime<Tz> = row.get("ReceivedAt")?;
+
+        letters.push(DeadLetter {
+            id: row.get("ID")?,
+            reason: RejectionReason::from_code(reason.as_str())
+                .ok_or_else(|| format!("unknown rejection reason {}", reason))?,
+            product: row.get("Product")?,
+            message: row.get("Message")?,
+            body: row.get("Body")?,
+            encoding: BodyEncoding::from_code(encoding.as_str())
+                .ok_or_else(|| format!("unknown body encoding {}", encoding))?,
+            received_at: received_at.with_timezone(&Utc),
+        });
+    }
+
+    Ok(letters)
+}
+
+/// Returns the expression that truncates the given time column to the start of its bucket,
+/// following the wall clock of the given timezone.
+fn bucket(granularity: Granularity, column: &str, tz: &str) -> String {
+    let tz = quote(tz);
+    let time = format!("toDateTime({}, {})", column, tz);
+    let start = match granularity {
+        Granularity::Minute => format!("toStartOfMinute({})", time),
+        Granularity::Hour => format!("toStartOfHour({})", time),
+        Granularity::Day => format!("toStartOfDay({})", time),
+        Granularity::Week => format!("toStartOfWeek({}, 1)", time),
+    };
+
+    // `toStartOfWeek` returns a `Date`, so everything is converted back to a `DateTime`.
+    format!("toDateTime({}, {})", start, tz)
+}
+
+/// Returns the columns that group events by the given dimensions, as `G0`, `G1`, ...
+fn dimensions(dimensions: &[Dimension]) -> Vec<String> {
+    dimensions
+        .iter()
+        .enumerate()
+        .map(|(i, dimension)| {
+            let expression = match dimension {
+                Dimension::Product => "Product".into(),
+                Dimension::Version => "Version".into(),
+                Dimension::OS => "OS".into(),
+                Dimension::Arch => "Arch".into(),
+                Dimension::Distribution => "Distribution".into(),
+                Dimension::Data(path) => json_text(path),
+            };
+
+            format!("{} AS G{}", expression, i)
+        })
+        .collect()
+}
+
+/// Returns the names of the columns from [`dimensions`].
+fn groups(dimensions: &[Dimension]) -> Vec<String> {
+    (0..dimensions.len()).map(|i| format!("G{}", i)).collect()
+}
+
+/// Reads the values of the columns from [`dimensions`] in a row.
+fn read_group<K: ColumnType>(row: &Row<'_, K>, dimensions: usize) -> Result<Vec<Option<String>>> {
+    let mut group = vec![];
+    for i in 0..dimensions {
+        // missing JSON paths are extracted as an empty string.
+        let value: String = row.get(format!("G{}", i).as_str())?;
+        group.push(Some(value).filter(|v| !v.is_empty()));
+    }
+
+    Ok(group)
+}
+
+/// Returns the conditions for the filters of the given query, where `time` is the column
+/// that has when the events were fired at.
+fn conditions(query: &EventQuery, time: &str) -> Vec<String> {
+    let mut conditions = query
+        .filters()
+        .into_iter()
+        .map(|(column, value)| format!("{} = {}", column, quote(value)))
+        .collect::<Vec<_>>();
+
+    if let Some(from) = query.from {
+        conditions.push(format!("{} >= {}", time, datetime(from)));
+    }
+
+    if let Some(to) = query.to {
+        conditions.push(format!("{} < {}", time, datetime(to)));
+    }
+
+    if let Some(before) = query.before {
+        conditions.push(format!("ID < {}", before));
+    }
+
+    for filter in query.data.iter() {
+        conditions.push(format!(
+            "{} = {}",
+            json_text(&filter.path),
+            quote(&filter.value)
+        ));
+    }
+
+    conditions
+}
+
+/// Returns the `WHERE` clause for the given conditions, if there are any.
+fn where_clause(conditions: &[String]) -> String {
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!(" WHERE {}", conditions.join(" AND "))
+    }
+}
+
+/// Returns the expression that extracts the value at the given path in the `Data` column,
+/// the same way [`analytics::json_text`](crate::analytics::json_text) does.
+fn json_text(path: &[String]) -> String {
+    let path = path
+        .iter()
+        .map(|key| quote(key))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "if(JSONType(Data, {path}) = 'String', JSONExtractString(Data, {path}), JSONExtractRaw(Data, {path}))"
+    )
+}
+
+/// Returns the given time as a ClickHouse `DateTime64` literal.
+fn datetime(time: DateTime<Utc>) -> String {
+    format!(
+        "toDateTime64('{}', 3, 'UTC')",
+        time.format("%Y-%m-%d %H:%M:%S%.3f")
+    )
+}
+
+/// Builds the TTL expressions for a table out of the retention configuration, with the
+/// time of its rows in `column`, or an empty string if events are kept forever.
+fn ttl_clause(retention: &RetentionConfig, column: &str) -> String {
+    let products = retention.products.clone().unwrap_or_default();
+    let mut rules = products
+        .iter()
+        .map(|(product, days)| {
+            format!(
+                "toDateTime({}) + INTERVAL {} DAY DELETE WHERE Product = {}",
+                column,
+                days,
+                quote(product)
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(days) = retention.default_days {
+        if products.is_empty() {
+            rules.push(format!("toDateTime({}) + INTERVAL {} DAY", column, days));
+        } else {
+            rules.push(format!(
+                "toDateTime({}) + INTERVAL {} DAY DELETE WHERE Product NOT IN ({})",
+                column,
+                days,
+                products
+                    .keys()
+                    .map(|p| quote(p))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+    }
+
+    rules.join(", ")
+}
+
+/// Quotes the given value as a ClickHouse string literal, so it is safe
+/// to interpolate in a query.
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::RetentionConfig;
+
+    #[test]
+    fn ttl_clause_covers_every_product() {
+        assert_eq!(
+            super::ttl_clause(&RetentionConfig::default(), "FiredAt"),
+            ""
+        );
+        assert_eq!(
+            super::ttl_clause(
+                &RetentionConfig {
+                    default_days: Some(365),
+                    products: None,
+                },
+                "Time"
+            ),
+            "toDateTime(Time) + INTERVAL 365 DAY"
+        );
+
+        assert_eq!(
+            super::ttl_clause(&RetentionConfig {
+                default_days: Some(365),
+                products: Some([("ume".to_string(), 30)].into()),
+            }, "FiredAt"),
+            "toDateTime(FiredAt) + INTERVAL 30 DAY DELETE WHERE Product = 'ume', toDateTime(FiredAt) + INTERVAL 365 DAY DELETE WHERE Product NOT IN ('ume')"
+        );
+    }
 }
@@ -0,0 +1,252 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, FixedOffset, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    analytics::{self, DataFilter, EventQuery, Granularity},
+    storage::Event,
+};
+
+/// Query for the cohort retention endpoint. Installations are put in the cohort of the
+/// bucket they first reported in, and are retained in a later bucket if they had enough
+/// activity in it.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct CohortQuery {
+    pub product: String,
+
+    /// Only cohorts of installations that first reported in this range are returned.
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub granularity: Option<Granularity>, // defaults to "week"
+    pub periods: Option<u32>, // defaults to 12, how many periods are reported after the first one
+    pub min_events: Option<u64>, // defaults to 1, how many events make an installation active in a period
+    pub timezone: Option<String>, // defaults to "UTC"
+
+    /// Which events count as activity, given as `data.<path>=<value>` in the query string.
+    #[serde(skip)]
+    pub data: Vec<DataFilter>,
+}
+
+impl CohortQuery {
+    pub fn granularity(&self) -> Granularity {
+        self.granularity.unwrap_or(Granularity::Week)
+    }
+
+    pub fn periods(&self) -> u32 {
+        self.periods.unwrap_or(12).clamp(1, 104)
+    }
+
+    pub fn min_events(&self) -> u64 {
+        self.min_events.unwrap_or(1).max(1)
+    }
+
+    pub fn tz(&self) -> Result<Tz, String> {
+        analytics::timezone(self.timezone.as_deref())
+    }
+
+    /// Returns every event of the product, since cohorts depend on when installations
+    /// were first seen.
+    pub fn events(&self) -> EventQuery {
+        EventQuery {
+            product: Some(self.product.clone()),
+            ..Default::default()
+        }
+    }
+
+    /// Returns how many periods are between the two buckets.
+    pub fn period(&self, cohort: DateTime<Utc>, bucket: DateTime<Utc>) -> i64 {
+        // buckets in a timezone aren't always the same length when the clocks change.
+        let seconds = (bucket - cohort).num_seconds() as f64;
+        (seconds / self.granularity().seconds() as f64).round() as i64
+    }
+
+    /// Computes the retention out of every event of the product, for the backends that
+    /// can't do it themselves.
+    pub fn compute(&self, events: Vec<Event>) -> CohortMatrix {
+        let tz = self.tz().unwrap_or(Tz::UTC);
+        let truncate = |time| {
+            self.granularity()
+                .truncate_in(time, &tz)
+                .with_timezone(&Utc)
+        };
+
+        let mut first_seen = BTreeMap::<&str, DateTime<Utc>>::new();
+        let mut activity = BTreeMap::<(&str, DateTime<Utc>), u64>::new();
+        for event in events.iter() {
+            let Some(id) = event.installation_id.as_deref() else {
+                continue;
+            };
+
+            let seen = first_seen.entry(id).or_insert(event.fired_at);
+            *seen = (*seen).min(event.fired_at);
+
+            if self.data.iter().all(|filter| filter.matches(&event.data)) {
+                *activity.entry((id, truncate(event.fired_at))).or_default() += 1;
+            }
+        }
+
+        let cohorts = first_seen
+            .into_iter()
+            .filter(|(_, seen)| {
+                self.from.is_none_or(|from| *seen >= from) && self.to.is_none_or(|to| *seen < to)
+            })
+            .map(|(id, seen)| (id, truncate(seen)))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut sizes = BTreeMap::<DateTime<Utc>, u64>::new();
+        for cohort in cohorts.values() {
+            *sizes.entry(*cohort).or_default() += 1;
+        }
+
+        let mut retained = BTreeMap::<(DateTime<Utc>, i64), u64>::new();
+        for ((id, bucket), count) in activity {
+            if count < self.min_events() {
+                continue;
+            }
+
+            if let Some(cohort) = cohorts.get(id) {
+                *retained
+                    .entry((*cohort, self.period(*cohort, bucket)))
+                    .or_default() += 1;
+            }
+        }
+
+        self.matrix(
+            sizes,
+            retained
+                .into_iter()
+                .map(|((cohort, period), count)| (cohort, period, count)),
+            Utc::now(),
+        )
+    }
+
+    /// Builds the triangular matrix out of `(cohort, installations)` and
+    /// `(cohort, period, retained)` rows, where periods that are after `now` are left out.
+    pub fn matrix<S, R>(&self, sizes: S, retained: R, now: DateTime<Utc>) -> CohortMatrix
+    where
+        S: IntoIterator<Item = (DateTime<Utc>, u64)>,
+        R: IntoIterator<Item = (DateTime<Utc>, i64, u64)>,
+    {
+        let tz = self.tz().unwrap_or(Tz::UTC);
+        let current = self.granularity().truncate_in(now, &tz).with_timezone(&Utc);
+
+        let mut retention = BTreeMap::<DateTime<Utc>, BTreeMap<i64, u64>>::new();
+        for (cohort, period, count) in retained {
+            retention.entry(cohort).or_default().insert(period, count);
+        }
+
+        CohortMatrix {
+            product: self.product.clone(),
+            granularity: self.granularity(),
+            timezone: tz.name().to_string(),
+            cohorts: sizes
+                .into_iter()
+                .map(|(cohort, installations)| {
+                    let observed =
+                        (self.period(cohort, current) + 1).clamp(1, self.periods() as i64 + 1);
+
+                    let counts = retention.remove(&cohort).unwrap_or_default();
+                    let retained = (0..observed)
+                        .map(|period| counts.get(&period).copied().unwrap_or(0))
+                        .collect::<Vec<_>>();
+
+                    Cohort {
+                        cohort: cohort.with_timezone(&tz).fixed_offset(),
+                        installations,
+                        rates: retained
+                            .iter()
+                            .map(|count| *count as f64 / installations as f64)
+                            .collect(),
+                        retained,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Represents the installations that first reported in the same bucket, and how many
+/// of them were active in each period after it, starting with the first one.
+#[derive(Serialize, Debug, Clone)]
+pub struct Cohort {
+    pub cohort: DateTime<FixedOffset>,
+    pub installations: u64,
+    pub retained: Vec<u64>,
+    pub rates: Vec<f64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CohortMatrix {
+    pub product: String,
+    pub granularity: Granularity,
+    pub timezone: String,
+    pub cohorts: Vec<Cohort>,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use super::CohortQuery;
+    use crate::storage::Event;
+
+    #[test]
+    fn builds_a_triangular_matrix() {
+        let monday = Utc.with_ymd_and_hms(2023, 3, 6, 12, 0, 0).unwrap();
+        let event = |id: u64, installation: &str, weeks: i64| {
+            Event::test(id)
+                .with_installation(installation)
+                .fired_at(monday + Duration::weeks(weeks))
+        };
+
+        let query = CohortQuery {
+            product: "charted-server".into(),
+            periods: Some(3),
+            ..Default::default()
+        };
+
+        let events = [
+            event(1, "a", 0),
+            event(2, "b", 0),
+            event(3, "a", 1),
+            event(4, "a", 2),
+            event(5, "c", 1),
+        ]
+        .into_iter()
+        .collect::<Vec<_>>();
+
+        let matrix = query.compute(events);
+        assert_eq!(matrix.cohorts.len(), 2);
+        assert_eq!(matrix.cohorts[0].installations, 2);
+        assert_eq!(matrix.cohorts[0].retained, vec![2, 1, 1, 0]);
+        assert_eq!(matrix.cohorts[0].rates[1], 0.5);
+        assert_eq!(matrix.cohorts[1].retained, vec![1, 0, 0, 0]);
+
+        // cohorts only have the periods that already happened
+        let matrix = query.matrix(
+            [(monday, 1), (monday + Duration::weeks(1), 1)],
+            [],
+            monday + Duration::weeks(1),
+        );
+
+        assert_eq!(matrix.cohorts[0].retained.len(), 2);
+        assert_eq!(matrix.cohorts[1].retained.len(), 1);
+    }
+}
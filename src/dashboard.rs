@@ -0,0 +1,270 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+use actix_web::{http::header::AUTHORIZATION, HttpRequest};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64, Engine};
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    analytics::{AggregateQuery, Aggregation, Dimension, Granularity, Point},
+    config::{DashboardConfig, Secret},
+};
+
+/// Name of the cookie that keeps the dashboard session after logging in.
+pub const COOKIE: &str = "telemetry_dashboard";
+
+/// How long a dashboard session lasts after logging in.
+pub const SESSION_DAYS: i64 = 7;
+
+/// Represents a static file of the dashboard, which is compiled into the binary.
+#[derive(Debug)]
+pub struct Asset {
+    pub name: &'static str,
+    pub content_type: &'static str,
+    pub body: &'static str,
+
+    /// If the asset can be served before logging in, since the login page uses it.
+    pub public: bool,
+}
+
+pub const INDEX: &str = include_str!("../assets/dashboard/index.html");
+pub const LOGIN: &str = include_str!("../assets/dashboard/login.html");
+
+pub const ASSETS: &[Asset] = &[
+    Asset {
+        name: "dashboard.css",
+        content_type: "text/css; charset=utf-8",
+        body: include_str!("../assets/dashboard/dashboard.css"),
+        public: true,
+    },
+    Asset {
+        name: "dashboard.js",
+        content_type: "text/javascript; charset=utf-8",
+        body: include_str!("../assets/dashboard/dashboard.js"),
+        public: false,
+    },
+];
+
+/// Returns the asset with the given name, if there is one.
+pub fn asset(name: &str) -> Option<&'static Asset> {
+    ASSETS.iter().find(|asset| asset.name == name)
+}
+
+/// Returns the token the dashboard is protected by, or `None` if it is disabled.
+pub fn token(config: Option<&DashboardConfig>) -> Option<&Secret> {
+    config
+        .and_then(|c| c.token.as_ref())
+        .filter(|token| !token.expose().is_empty())
+}
+
+fn sign(token: &Secret, expires_at: i64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(token.expose().as_bytes())
+        .expect("HMAC takes keys of any size");
+
+    mac.update(expires_at.to_string().as_bytes());
+    mac
+}
+
+/// Returns the value of the session cookie, which is signed with the dashboard token so
+/// the token itself is never stored in the browser, and changing it ends every session.
+pub fn session(token: &Secret, expires_at: DateTime<Utc>) -> String {
+    let expires_at = expires_at.timestamp();
+    let signature = sign(token, expires_at).finalize().into_bytes();
+
+    format!("{}.{}", expires_at, BASE64.encode(signature))
+}
+
+/// Checks if the session cookie was signed with the dashboard token and hasn't
+/// expired yet.
+pub fn verify_session(value: &str, token: &Secret, now: DateTime<Utc>) -> bool {
+    let (expires_at, signature) = match value.split_once('.') {
+        Some(parts) => parts,
+        None => return false,
+    };
+
+    match (expires_at.parse::<i64>(), BASE64.decode(signature)) {
+        (Ok(expires_at), Ok(signature)) => {
+            expires_at > now.timestamp() && sign(token, expires_at).verify_slice(&signature).is_ok()
+        }
+
+        _ => false,
+    }
+}
+
+/// Checks if the request has a dashboard session, from the cookie that is set when
+/// logging in, or the token itself in the `Authorization` header.
+pub fn is_authorized(req: &HttpRequest, token: &Secret) -> bool {
+    if req
+        .cookie(COOKIE)
+        .is_some_and(|c| verify_session(c.value(), token, Utc::now()))
+    {
+        return true;
+    }
+
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.strip_prefix("Bearer ").unwrap_or(h))
+        .is_some_and(|value| token.matches(value))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LoginForm {
+    pub token: String,
+}
+
+/// Query for the overview that the dashboard shows.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct OverviewQuery {
+    pub product: Option<String>,
+    pub days: Option<u32>, // defaults to 30, at most 365
+}
+
+impl OverviewQuery {
+    pub fn days(&self) -> i64 {
+        self.days.unwrap_or(30).clamp(1, 365) as i64
+    }
+
+    /// Short ranges are shown by hour, the rest by day.
+    pub fn granularity(&self) -> Granularity {
+        if self.days() <= 2 {
+            Granularity::Hour
+        } else {
+            Granularity::Day
+        }
+    }
+
+    /// Returns the aggregation the overview is computed from, which has a series for
+    /// every combination of product, version, OS and architecture.
+    pub fn aggregate(&self, now: DateTime<Utc>) -> AggregateQuery {
+        let granularity = self.granularity();
+
+        AggregateQuery {
+            product: self.product.clone(),
+            from: Some(granularity.truncate(now - Duration::days(self.days()))),
+            to: Some(now),
+            granularity: Some(granularity),
+            dimensions: vec![
+                Dimension::Product,
+                Dimension::Version,
+                Dimension::OS,
+                Dimension::Arch,
+            ],
+            ..Default::default()
+        }
+    }
+}
+
+/// Represents how many events had a value of a dimension.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Breakdown {
+    pub value: Option<String>,
+    pub count: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Overview {
+    pub granularity: Granularity,
+    pub from: DateTime<Utc>,
+    pub total: u64,
+    pub volume: Vec<Point>,
+    pub breakdowns: BTreeMap<String, Vec<Breakdown>>,
+}
+
+impl Overview {
+    /// Sums the series of the aggregation into the event volume over time, and how many
+    /// events each product, version, OS and architecture had. Buckets without any events
+    /// are filled in, so the volume can be charted as-is.
+    pub fn new(query: &AggregateQuery, aggregation: Aggregation) -> Overview {
+        let granularity = aggregation.granularity;
+        let from = query.from.unwrap_or_else(Utc::now);
+        let to = query.to.unwrap_or_else(Utc::now);
+
+        let mut volume = BTreeMap::<DateTime<FixedOffset>, u64>::new();
+        let mut bucket = granularity.truncate(from);
+        while bucket <= to {
+            volume.insert(bucket.fixed_offset(), 0);
+            bucket += Duration::seconds(granularity.seconds());
+        }
+
+        let mut breakdowns = BTreeMap::<String, BTreeMap<Option<String>, u64>>::new();
+        let mut total = 0;
+        for series in aggregation.series {
+            total += series.total;
+            for point in series.points {
+                *volume.entry(point.time).or_default() += point.count;
+            }
+
+            for (dimension, value) in series.group {
+                *breakdowns
+                    .entry(dimension)
+                    .or_default()
+                    .entry(value)
+                    .or_default() += series.total;
+            }
+        }
+
+        Overview {
+            granularity,
+            from,
+            total,
+            volume: volume
+                .into_iter()
+                .map(|(time, count)| Point { time, count })
+                .collect(),
+            breakdowns: breakdowns
+                .into_iter()
+                .map(|(dimension, values)| {
+                    let mut values = values
+                        .into_iter()
+                        .map(|(value, count)| Breakdown { value, count })
+                        .collect::<Vec<_>>();
+
+                    values.sort_by(|a, b| b.count.cmp(&a.count).then(a.value.cmp(&b.value)));
+                    (dimension, values)
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use crate::config::Secret;
+
+    #[test]
+    fn sessions_are_signed_with_the_token() {
+        let token = Secret::from("uwu");
+        let now = Utc::now();
+        let session = super::session(&token, now + Duration::days(1));
+
+        assert!(!session.contains("uwu"));
+        assert!(super::verify_session(&session, &token, now));
+        assert!(!super::verify_session(
+            &session,
+            &token,
+            now + Duration::days(2)
+        ));
+        assert!(!super::verify_session(&session, &Secret::from("owo"), now));
+        assert!(!super::verify_session("uwu", &token, now));
+    }
+}
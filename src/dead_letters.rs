@@ -0,0 +1,724 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration as StdDuration, Instant, SystemTime},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{config::DeadLetterConfig, storage::EventStore};
+
+/// Reason why a telemetry event never made it into `telemetry.events`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RejectionReason {
+    /// The request body couldn't be deserialized into a `TrackBody`.
+    InvalidJson,
+
+    /// The body was valid JSON, but one of the fields didn't pass validation.
+    ValidationFailed,
+
+    /// The event was valid, but the storage backend refused (or failed) to store it.
+    InsertFailed,
+}
+
+impl RejectionReason {
+    /// If the client is the one to blame, in which case it can send as many of these as it
+    /// wants, so we only keep a few of them.
+    pub fn is_client_error(&self) -> bool {
+        matches!(
+            self,
+            RejectionReason::InvalidJson | RejectionReason::ValidationFailed
+        )
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            RejectionReason::InvalidJson => "INVALID_JSON",
+            RejectionReason::ValidationFailed => "VALIDATION_FAILED",
+            RejectionReason::InsertFailed => "INSERT_FAILED",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<RejectionReason> {
+        match code {
+            "INVALID_JSON" => Some(RejectionReason::InvalidJson),
+            "VALIDATION_FAILED" => Some(RejectionReason::ValidationFailed),
+            "INSERT_FAILED" => Some(RejectionReason::InsertFailed),
+            _ => None,
+        }
+    }
+}
+
+/// How the body of a dead letter is stored. Bodies that aren't valid UTF-8 are kept
+/// as base64, so they can be replayed byte for byte.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyEncoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+impl BodyEncoding {
+    pub fn code(&self) -> &'static str {
+        match self {
+            BodyEncoding::Utf8 => "utf8",
+            BodyEncoding::Base64 => "base64",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<BodyEncoding> {
+        match code {
+            "utf8" => Some(BodyEncoding::Utf8),
+            "base64" => Some(BodyEncoding::Base64),
+            _ => None,
+        }
+    }
+}
+
+/// Represents a rejected telemetry event, with the raw body that was sent to us.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: u64,
+    pub reason: RejectionReason,
+    pub product: Option<String>,
+    pub message: String,
+    pub body: String,
+
+    // Dead letters written before bodies were encoded are always UTF-8.
+    #[serde(default)]
+    pub encoding: BodyEncoding,
+    pub received_at: DateTime<Utc>,
+}
+
+impl DeadLetter {
+    pub fn new(
+        id: u64,
+        reason: RejectionReason,
+        body: &[u8],
+        message: String,
+        received_at: DateTime<Utc>,
+    ) -> DeadLetter {
+        // We try our best to know which product sent this, even if the body
+        // didn't deserialize into a `TrackBody`.
+        let product = serde_json::from_slice::<Value>(body)
+            .ok()
+            .and_then(|value| {
+                value
+                    .get("product")
+                    .and_then(|p| p.as_str())
+                    .map(|p| p.to_string())
+            });
+
+        let (body, encoding) = match std::str::from_utf8(body) {
+            Ok(body) => (body.to_string(), BodyEncoding::Utf8),
+            Err(_) => (BASE64.encode(body), BodyEncoding::Base64),
+        };
+
+        DeadLetter {
+            id,
+            reason,
+            product,
+            message,
+            body,
+            encoding,
+            received_at,
+        }
+    }
+
+    /// Returns the body exactly as it was sent to us.
+    pub fn body_bytes(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        match self.encoding {
+            BodyEncoding::Utf8 => Ok(self.body.as_bytes().to_vec()),
+            BodyEncoding::Base64 => BASE64.decode(&self.body),
+        }
+    }
+}
+
+/// Filters that can be applied when listing dead letters.
+#[derive(Debug, Default, Deserialize)]
+pub struct DeadLetterQuery {
+    pub reason: Option<String>,
+    pub product: Option<String>,
+    pub limit: Option<u64>,
+}
+
+impl DeadLetterQuery {
+    pub fn matches(&self, letter: &DeadLetter) -> bool {
+        if let Some(reason) = &self.reason {
+            if letter.reason.code() != reason {
+                return false;
+            }
+        }
+
+        if let Some(product) = &self.product {
+            if letter.product.as_ref() != Some(product) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit.unwrap_or(50).clamp(1, 500)
+    }
+}
+
+/// Sink for rejected events. Dead letters are stored in the storage backend (the
+/// `telemetry.dead_letters` table for ClickHouse) and persisted as JSON files in the
+/// configured directory, so they survive the backend being unavailable and can be
+/// re-ingested with `telemetry-server replay`.
+///
+/// Since anyone can send us invalid events, the files are capped by `max_files` and
+/// `max_bytes` (deleting the oldest ones first), and only `per_client_per_minute` invalid
+/// bodies are kept from the same client.
+#[derive(Debug, Clone)]
+pub struct DeadLetters {
+    store: Arc<dyn EventStore>,
+    directory: Option<PathBuf>,
+    max_files: u64,
+    max_bytes: u64,
+    per_client_per_minute: u32,
+
+    // size of every file in `directory`, keyed by its ID so the oldest one comes first. This
+    // is read from the directory on the first write, and re-read when the retention is applied.
+    files: Arc<tokio::sync::Mutex<Option<BTreeMap<u64, u64>>>>,
+
+    // when each client's current minute started, and how many bodies we kept in it.
+    clients: Arc<Mutex<HashMap<IpAddr, (Instant, u32)>>>,
+}
+
+impl DeadLetters {
+    pub fn new(store: Arc<dyn EventStore>, config: Option<&DeadLetterConfig>) -> DeadLetters {
+        let persist_files = config.and_then(|c| c.persist_files).unwrap_or(true);
+        let directory = config
+            .and_then(|c| c.directory.clone())
+            .unwrap_or_else(|| "./dead-letters".into());
+
+        DeadLetters {
+            store,
+            directory: if persist_files {
+                Some(PathBuf::from(directory))
+            } else {
+                None
+            },
+            max_files: config.and_then(|c| c.max_files).unwrap_or(10_000),
+            max_bytes: config
+                .and_then(|c| c.max_bytes)
+                .unwrap_or(256 * 1024 * 1024),
+            per_client_per_minute: config.and_then(|c| c.per_client_per_minute).unwrap_or(10),
+            files: Arc::new(tokio::sync::Mutex::new(None)),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Checks if a dead letter from the given client should be kept. Insert failures are
+    /// always kept, since they're our fault, but only `per_client_per_minute` bodies the
+    /// client is to blame for are.
+    pub fn admit(&self, client: Option<IpAddr>, reason: RejectionReason) -> bool {
+        if !reason.is_client_error() {
+            return true;
+        }
+
+        let minute = StdDuration::from_secs(60);
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+
+        // forget the clients whose minute is over, so this doesn't grow forever.
+        if clients.len() >= 10_000 {
+            clients.retain(|_, (started, _)| now.duration_since(*started) < minute);
+        }
+
+        let (started, kept) = clients
+            .entry(client.unwrap_or(IpAddr::from([0, 0, 0, 0])))
+            .or_insert((now, 0));
+
+        if now.duration_since(*started) >= minute {
+            *started = now;
+            *kept = 0;
+        }
+
+        if *kept >= self.per_client_per_minute {
+            return false;
+        }
+
+        *kept += 1;
+        true
+    }
+
+    /// Stores the given dead letter. This never fails, since there is nothing left
+    /// to do with the event if both the storage backend and the file system refused it.
+    pub async fn store(&self, letter: DeadLetter) {
+        warn!(
+            "rejected event {} ({}): {}",
+            letter.id,
+            letter.reason.code(),
+            letter.message
+        );
+
+        self.write_file(&letter).await;
+        if let Err(error) = self.store.insert_dead_letter(&letter).await {
+            error!(
+                "unable to store dead letter {} in {}: {}",
+                letter.id,
+                self.store.name(),
+                error
+            );
+        }
+    }
+
+    /// Deletes the dead letters that were received more than `days` days ago, from both
+    /// the storage backend and the local directory.
+    pub async fn apply_retention(&self, days: u32) {
+        if let Err(error) = self.store.apply_dead_letter_retention(days).await {
+            error!(
+                "unable to apply dead letter retention on {}: {}",
+                self.store.name(),
+                error
+            );
+        }
+
+        let cutoff = SystemTime::now() - StdDuration::from_secs(days as u64 * 24 * 60 * 60);
+        let mut files = self.files.lock().await;
+        let mut kept = BTreeMap::new();
+        let mut deleted = 0;
+
+        for (id, path, size, modified) in self.scan_files().await {
+            if modified < cutoff {
+                match tokio::fs::remove_file(&path).await {
+                    Ok(()) => deleted += 1,
+                    Err(error) => {
+                        warn!("unable to delete dead letter {}: {}", path.display(), error)
+                    }
+                }
+            } else {
+                kept.insert(id, size);
+            }
+        }
+
+        if deleted > 0 {
+            info!("deleted {} dead letter files that expired", deleted);
+        }
+
+        *files = Some(kept);
+    }
+
+    /// Returns the directory that failed bodies are persisted in, if `persist_files` is set.
+    pub fn directory(&self) -> Option<&PathBuf> {
+        self.directory.as_ref()
+    }
+
+    async fn write_file(&self, letter: &DeadLetter) {
+        let directory = match &self.directory {
+            Some(dir) => dir,
+            None => {
+                debug!(
+                    "not persisting dead letter {} since the local directory is disabled",
+                    letter.id
+                );
+                return;
+            }
+        };
+
+        if let Err(error) = tokio::fs::create_dir_all(directory).await {
+            error!(
+                "unable to create dead letter directory {}: {}",
+                directory.display(),
+                error
+            );
+            return;
+        }
+
+        let path = directory.join(format!("{}.json", letter.id));
+        let contents = match serde_json::to_vec_pretty(letter) {
+            Ok(contents) => contents,
+            Err(error) => {
+                error!("unable to serialize dead letter {}: {}", letter.id, error);
+                return;
+            }
+        };
+
+        let size = contents.len() as u64;
+        if let Err(error) = tokio::fs::write(&path, contents).await {
+            error!(
+                "unable to write dead letter to {}: {}",
+                path.display(),
+                error
+            );
+
+            return;
+        }
+
+        let mut files = self.files.lock().await;
+        if files.is_none() {
+            let scanned = self.scan_files().await;
+            *files = Some(
+                scanned
+                    .into_iter()
+                    .map(|(id, _, size, _)| (id, size))
+                    .collect(),
+            );
+        }
+
+        let files = files.as_mut().unwrap();
+        files.insert(letter.id, size);
+
+        // the oldest files are deleted first, until we're within both limits again.
+        let mut total = files.values().sum::<u64>();
+        while files.len() as u64 > self.max_files || total > self.max_bytes {
+            let (id, size) = match files.pop_first() {
+                Some(file) => file,
+                None => break,
+            };
+
+            total -= size;
+            let path = directory.join(format!("{}.json", id));
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => debug!("deleted dead letter {} to stay within the limits", id),
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => warn!("unable to delete dead letter {}: {}", path.display(), error),
+            }
+        }
+    }
+
+    /// Returns the ID, path, size and modification time of every dead letter that was
+    /// persisted on disk.
+    async fn scan_files(&self) -> Vec<(u64, PathBuf, u64, SystemTime)> {
+        let directory = match &self.directory {
+            Some(dir) => dir,
+            None => return vec![],
+        };
+
+        let mut entries = match tokio::fs::read_dir(directory).await {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+
+        let mut files = vec![];
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            let id = match path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                Some(id) if path.extension().and_then(|e| e.to_str()) == Some("json") => id,
+                _ => continue,
+            };
+
+            if let Ok(metadata) = entry.metadata().await {
+                let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+                files.push((id, path, metadata.len(), modified));
+            }
+        }
+
+        files
+    }
+
+    /// Reads every dead letter that was persisted on disk, alongside its path.
+    pub async fn read_files(&self) -> Vec<(PathBuf, DeadLetter)> {
+        let directory = match &self.directory {
+            Some(dir) => dir,
+            None => return vec![],
+        };
+
+        let mut entries = match tokio::fs::read_dir(directory).await {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+
+        let mut letters = vec![];
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+
+            match tokio::fs::read(&path).await {
+                Ok(contents) => match serde_json::from_slice::<DeadLetter>(&contents) {
+                    Ok(letter) => letters.push((path, letter)),
+                    Err(error) => warn!(
+                        "skipping malformed dead letter {}: {}",
+                        path.display(),
+                        error
+                    ),
+                },
+                Err(error) => warn!("unable to read dead letter {}: {}", path.display(), error),
+            }
+        }
+
+        letters
+    }
+
+    /// Returns the IDs of the dead letters that were persisted on disk, newest first,
+    /// without reading them.
+    async fn file_ids(&self) -> Vec<u64> {
+        let directory = match &self.directory {
+            Some(dir) => dir,
+            None => return vec![],
+        };
+
+        let mut entries = match tokio::fs::read_dir(directory).await {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+
+        let mut ids: Vec<u64> = vec![];
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse().ok())
+            {
+                ids.push(id);
+            }
+        }
+
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+        ids
+    }
+
+    /// Reads the dead letter with the given ID from disk, if it was persisted.
+    async fn read_file(&self, id: u64) -> Option<DeadLetter> {
+        let path = self.directory.as_ref()?.join(format!("{}.json", id));
+        let contents = match tokio::fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(error) => {
+                warn!("unable to read dead letter {}: {}", path.display(), error);
+                return None;
+            }
+        };
+
+        match serde_json::from_slice(&contents) {
+            Ok(letter) => Some(letter),
+            Err(error) => {
+                warn!(
+                    "skipping malformed dead letter {}: {}",
+                    path.display(),
+                    error
+                );
+
+                None
+            }
+        }
+    }
+
+    /// Moves a replayed dead letter into the `replayed/` directory, so it isn't
+    /// replayed twice but is still around for auditing.
+    pub async fn mark_replayed(&self, path: &Path) -> std::io::Result<()> {
+        let directory = match path.parent() {
+            Some(parent) => parent.join("replayed"),
+            None => return Ok(()),
+        };
+
+        tokio::fs::create_dir_all(&directory).await?;
+        tokio::fs::rename(path, directory.join(path.file_name().unwrap())).await?;
+
+        let id = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok());
+
+        if let (Some(files), Some(id)) = (self.files.lock().await.as_mut(), id) {
+            files.remove(&id);
+        }
+
+        Ok(())
+    }
+
+    /// Lists the most recent dead letters from both the storage backend and the
+    /// local directory.
+    pub async fn list(&self, query: &DeadLetterQuery) -> Vec<DeadLetter> {
+        // Letters are keyed by their ID, so the newest ones come last.
+        let mut letters = BTreeMap::new();
+        match self.store.dead_letters(query).await {
+            Ok(rows) => letters.extend(rows.into_iter().map(|l| (l.id, l))),
+            Err(error) => error!(
+                "unable to list dead letters from {}: {}",
+                self.store.name(),
+                error
+            ),
+        }
+
+        // Files are read newest first, and only until we have enough of them.
+        let limit = query.limit() as usize;
+        let mut found = 0;
+        for id in self.file_ids().await {
+            if found == limit {
+                break;
+            }
+
+            if let Some(letter) = self.read_file(id).await {
+                if query.matches(&letter) {
+                    letters.insert(letter.id, letter);
+                    found += 1;
+                }
+            }
+        }
+
+        letters
+            .into_values()
+            .rev()
+            .take(query.limit() as usize)
+            .collect()
+    }
+
+    /// Returns a single dead letter, if it exists.
+    pub async fn get(&self, id: u64) -> Option<DeadLetter> {
+        match self.store.dead_letter(id).await {
+            Ok(Some(letter)) => return Some(letter),
+            Ok(None) => {}
+            Err(error) => error!(
+                "unable to fetch dead letter {} from {}: {}",
+                id,
+                self.store.name(),
+                error
+            ),
+        }
+
+        self.read_file(id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, sync::Arc};
+
+    use chrono::Utc;
+
+    use super::{BodyEncoding, DeadLetter, DeadLetters, RejectionReason};
+    use crate::{config::DeadLetterConfig, memory::Memory};
+
+    #[tokio::test]
+    async fn limits_what_clients_can_leave_behind() {
+        let directory =
+            std::env::temp_dir().join(format!("telemetry-dead-letters-{}", std::process::id()));
+        let dead_letters = DeadLetters::new(
+            Arc::new(Memory::new()),
+            Some(&DeadLetterConfig {
+                directory: Some(directory.to_string_lossy().into_owned()),
+                max_files: Some(3),
+                per_client_per_minute: Some(2),
+                ..Default::default()
+            }),
+        );
+
+        let client = Some(IpAddr::from([127, 0, 0, 1]));
+        let admitted = (0..4)
+            .filter(|_| dead_letters.admit(client, RejectionReason::InvalidJson))
+            .count();
+
+        assert_eq!(admitted, 2);
+        assert!(dead_letters.admit(client, RejectionReason::InsertFailed));
+        assert!(dead_letters.admit(
+            Some(IpAddr::from([10, 0, 0, 1])),
+            RejectionReason::InvalidJson
+        ));
+
+        for id in 1..=5 {
+            dead_letters
+                .store(DeadLetter::new(
+                    id,
+                    RejectionReason::InvalidJson,
+                    b"{not json",
+                    "expected ident".into(),
+                    Utc::now(),
+                ))
+                .await;
+        }
+
+        let mut files = dead_letters
+            .scan_files()
+            .await
+            .into_iter()
+            .map(|(id, ..)| id)
+            .collect::<Vec<_>>();
+
+        files.sort_unstable();
+        std::fs::remove_dir_all(directory).unwrap();
+        assert_eq!(files, vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn extracts_product_from_rejected_bodies() {
+        let letter = DeadLetter::new(
+            1,
+            RejectionReason::ValidationFailed,
+            br#"{"product":"charted-server","vendor":""}"#,
+            "vendor: length".into(),
+            Utc::now(),
+        );
+
+        assert_eq!(letter.product, Some("charted-server".into()));
+
+        let letter = DeadLetter::new(
+            2,
+            RejectionReason::InvalidJson,
+            b"{not json",
+            "expected ident".into(),
+            Utc::now(),
+        );
+
+        assert_eq!(letter.product, None);
+        assert_eq!(letter.body, "{not json");
+        assert_eq!(letter.encoding, BodyEncoding::Utf8);
+    }
+
+    #[test]
+    fn keeps_non_utf8_bodies_intact() {
+        let body = b"{\"product\":\"\xff\xfe\"}";
+        let letter = DeadLetter::new(
+            3,
+            RejectionReason::InvalidJson,
+            body,
+            "invalid unicode".into(),
+            Utc::now(),
+        );
+
+        assert_eq!(letter.encoding, BodyEncoding::Base64);
+        assert_eq!(letter.body_bytes().unwrap(), body);
+
+        let json = serde_json::to_vec(&letter).unwrap();
+        let letter: DeadLetter = serde_json::from_slice(&json).unwrap();
+        assert_eq!(letter.body_bytes().unwrap(), body);
+    }
+
+    #[test]
+    fn rejection_reason_codes_roundtrip() {
+        for reason in [
+            RejectionReason::InvalidJson,
+            RejectionReason::ValidationFailed,
+            RejectionReason::InsertFailed,
+        ] {
+            assert_eq!(RejectionReason::from_code(reason.code()), Some(reason));
+        }
+    }
+}
@@ -0,0 +1,310 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    analytics::{self, DataFilter, EventQuery},
+    storage::Event,
+};
+
+/// ClickHouse's `windowFunnel` can't have more than 32 steps.
+pub const MAX_STEPS: usize = 32;
+
+/// Represents a step of a funnel, which is reached by an event that has every value
+/// in `data` (keyed by their JSON path, i.e. `setup.step`).
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FunnelStep {
+    pub name: Option<String>,
+
+    #[serde(default)]
+    pub data: BTreeMap<String, Value>,
+}
+
+/// Query for the funnel endpoint, which is sent as the request's body.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FunnelQuery {
+    pub product: String,
+    pub steps: Vec<FunnelStep>,
+    pub window: Option<u64>, // defaults to 86400, in seconds
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+
+    /// The filters of each step, parsed by [`FunnelQuery::validate`].
+    #[serde(skip)]
+    pub predicates: Vec<Vec<DataFilter>>,
+}
+
+impl FunnelQuery {
+    pub fn validate(&mut self) -> Result<(), String> {
+        if self.steps.is_empty() || self.steps.len() > MAX_STEPS {
+            return Err(format!(
+                "funnels must have between 1 and {} steps",
+                MAX_STEPS
+            ));
+        }
+
+        self.predicates = self
+            .steps
+            .iter()
+            .map(|step| {
+                step.data
+                    .iter()
+                    .map(|(path, value)| {
+                        Ok(DataFilter {
+                            path: analytics::parse_path(path)?,
+                            value: analytics::json_text(value),
+                        })
+                    })
+                    .collect::<Result<Vec<_>, String>>()
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(())
+    }
+
+    pub fn window(&self) -> u64 {
+        self.window.unwrap_or(86400).clamp(1, 90 * 86400)
+    }
+
+    pub fn events(&self) -> EventQuery {
+        EventQuery {
+            product: Some(self.product.clone()),
+            from: self.from,
+            to: self.to,
+            ..Default::default()
+        }
+    }
+
+    /// Returns how many steps the given events (of a single installation, in the order they
+    /// were fired) reached, the same way ClickHouse's `windowFunnel` does: every step has to
+    /// happen within the window of the event that started the chain.
+    pub fn level(&self, events: &[&Event]) -> usize {
+        let window = Duration::seconds(self.window() as i64);
+        let mut starts: Vec<Option<DateTime<Utc>>> = vec![None; self.predicates.len()];
+
+        for event in events {
+            // going backwards so an event can't be used for two steps of the same chain.
+            for (step, filters) in self.predicates.iter().enumerate().rev() {
+                if !filters.iter().all(|filter| filter.matches(&event.data)) {
+                    continue;
+                }
+
+                if step == 0 {
+                    starts[0] = Some(event.fired_at);
+                } else if let Some(start) = starts[step - 1] {
+                    if event.fired_at - start <= window {
+                        starts[step] = Some(start);
+                    }
+                }
+            }
+        }
+
+        starts
+            .iter()
+            .rposition(Option::is_some)
+            .map_or(0, |i| i + 1)
+    }
+
+    /// Computes the funnel out of the given events, for the backends that can't do it
+    /// themselves.
+    pub fn compute(&self, events: Vec<Event>) -> Funnel {
+        let mut installations = BTreeMap::<&str, Vec<&Event>>::new();
+        for event in events.iter() {
+            if let Some(id) = event.installation_id.as_deref() {
+                installations.entry(id).or_default().push(event);
+            }
+        }
+
+        let mut levels = BTreeMap::<(String, usize), u64>::new();
+        for (_, mut events) in installations {
+            events.sort_by_key(|e| (e.fired_at, e.id));
+
+            let level = self.level(&events);
+            if level == 0 {
+                continue;
+            }
+
+            // installations are broken down by the version they entered the funnel with.
+            let version = events
+                .iter()
+                .find(|e| self.predicates[0].iter().all(|f| f.matches(&e.data)))
+                .map(|e| e.version.clone())
+                .unwrap_or_default();
+
+            *levels.entry((version, level)).or_default() += 1;
+        }
+
+        self.funnel(
+            levels
+                .into_iter()
+                .map(|((version, level), count)| (version, level, count)),
+        )
+    }
+
+    /// Builds the funnel out of `(version, level, installations)` rows, where the level
+    /// is how many steps the installations reached.
+    pub fn funnel<I>(&self, rows: I) -> Funnel
+    where
+        I: IntoIterator<Item = (String, usize, u64)>,
+    {
+        let steps = self.steps.len();
+        let mut total = vec![0; steps];
+        let mut versions = BTreeMap::<String, Vec<u64>>::new();
+
+        for (version, level, count) in rows {
+            let reached = versions.entry(version).or_insert_with(|| vec![0; steps]);
+            for step in 0..level.min(steps) {
+                reached[step] += count;
+                total[step] += count;
+            }
+        }
+
+        Funnel {
+            product: self.product.clone(),
+            window: self.window(),
+            steps: self.reports(&total),
+            versions: versions
+                .into_iter()
+                .map(|(version, reached)| VersionFunnel {
+                    version,
+                    steps: self.reports(&reached),
+                })
+                .collect(),
+        }
+    }
+
+    fn reports(&self, reached: &[u64]) -> Vec<StepReport> {
+        reached
+            .iter()
+            .enumerate()
+            .map(|(i, installations)| StepReport {
+                name: self.steps[i]
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("step {}", i + 1)),
+                installations: *installations,
+                conversion: ratio(*installations, reached[0]),
+                drop_off: if i == 0 {
+                    0.0
+                } else {
+                    1.0 - ratio(*installations, reached[i - 1])
+                },
+            })
+            .collect()
+    }
+}
+
+fn ratio(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 / total as f64
+    }
+}
+
+/// Represents how many installations reached a step.
+#[derive(Serialize, Debug, Clone)]
+pub struct StepReport {
+    pub name: String,
+    pub installations: u64,
+
+    /// How many of the installations that reached the first step also reached this one.
+    pub conversion: f64,
+
+    /// How many of the installations that reached the previous step didn't reach this one.
+    pub drop_off: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct VersionFunnel {
+    pub version: String,
+    pub steps: Vec<StepReport>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Funnel {
+    pub product: String,
+    pub window: u64,
+    pub steps: Vec<StepReport>,
+    pub versions: Vec<VersionFunnel>,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use serde_json::{json, Value};
+
+    use super::FunnelQuery;
+    use crate::storage::Event;
+
+    #[test]
+    fn follows_window_funnel_semantics() {
+        let start = Utc.with_ymd_and_hms(2023, 4, 1, 0, 0, 0).unwrap();
+        let event = |id: u64, installation: &str, step: &str, minutes: i64| {
+            Event::test(id)
+                .with_installation(installation)
+                .with_data(json!({ "setup": { "step": step } }))
+                .fired_at(start + Duration::minutes(minutes))
+        };
+
+        let mut query: FunnelQuery = serde_json::from_value(json!({
+            "product": "charted-server",
+            "window": 3600,
+            "steps": [
+                { "name": "installed", "data": { "setup.step": "installed" } },
+                { "data": { "setup.step": "configured" } },
+                { "data": { "setup.step": "first_chart" } }
+            ]
+        }))
+        .unwrap();
+
+        query.validate().unwrap();
+        let funnel = query.compute(vec![
+            event(1, "a", "installed", 0),
+            event(2, "a", "configured", 10),
+            event(3, "a", "first_chart", 20),
+            event(4, "b", "installed", 0),
+            event(5, "b", "configured", 90),
+            event(6, "c", "configured", 0),
+        ]);
+
+        let reached = funnel
+            .steps
+            .iter()
+            .map(|s| s.installations)
+            .collect::<Vec<_>>();
+
+        assert_eq!(reached, vec![2, 1, 1]);
+        assert_eq!(funnel.steps[1].name, "step 2");
+        assert_eq!(funnel.steps[1].drop_off, 0.5);
+        assert_eq!(funnel.versions[0].version, "0.1.0");
+
+        query.steps.clear();
+        assert!(query.validate().is_err());
+
+        let mut query: FunnelQuery = serde_json::from_value(json!({
+            "product": "charted-server",
+            "steps": [{ "data": { "setup..step": Value::Null } }]
+        }))
+        .unwrap();
+
+        assert!(query.validate().is_err());
+    }
+}
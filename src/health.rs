@@ -0,0 +1,232 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    io::{BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{config::HealthConfig, storage::EventStore};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Down,
+}
+
+/// Represents the result of checking a single dependency.
+#[derive(Serialize, Debug, Clone)]
+pub struct Check {
+    pub status: Status,
+    pub latency_ms: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checked_at: DateTime<Utc>,
+    pub cached: bool,
+    pub checks: BTreeMap<String, Check>,
+}
+
+/// Checks if the dependencies of the server are usable, and caches the result so that
+/// frequent probes don't hammer the storage backend.
+#[derive(Debug, Clone)]
+pub struct Health {
+    store: Arc<dyn EventStore>,
+    cache_for: Duration,
+    timeout: Duration,
+    last: Arc<Mutex<Option<(Instant, Readiness)>>>,
+}
+
+impl Health {
+    pub fn new(store: Arc<dyn EventStore>, config: Option<&HealthConfig>) -> Health {
+        Health {
+            store,
+            cache_for: Duration::from_secs(config.and_then(|c| c.cache_seconds).unwrap_or(5)),
+            timeout: Duration::from_secs(config.and_then(|c| c.timeout_seconds).unwrap_or(2)),
+            last: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Returns if the server is ready to accept requests. Concurrent callers wait on the
+    /// same checks rather than running their own.
+    pub async fn readiness(&self) -> Readiness {
+        let mut last = self.last.lock().await;
+        if let Some((at, readiness)) = last.as_ref() {
+            if at.elapsed() < self.cache_for {
+                return Readiness {
+                    cached: true,
+                    ..readiness.clone()
+                };
+            }
+        }
+
+        let mut checks = BTreeMap::new();
+        let ping = self.check(self.store.ping()).await;
+        let tables = if ping.status == Status::Up {
+            self.check(async {
+                let missing = self.store.missing_tables().await?;
+                if missing.is_empty() {
+                    Ok(())
+                } else {
+                    Err(format!("missing tables: {}", missing.join(", ")).into())
+                }
+            })
+            .await
+        } else {
+            Check {
+                status: Status::Down,
+                latency_ms: 0,
+                error: Some(format!("{} is unreachable", self.store.name())),
+            }
+        };
+
+        checks.insert(self.store.name().to_string(), ping);
+        checks.insert("tables".to_string(), tables);
+
+        let readiness = Readiness {
+            ready: checks.values().all(|check| check.status == Status::Up),
+            checked_at: Utc::now(),
+            cached: false,
+            checks,
+        };
+
+        if !readiness.ready {
+            warn!("server isn't ready: {:?}", readiness.checks);
+        }
+
+        *last = Some((Instant::now(), readiness.clone()));
+        readiness
+    }
+
+    async fn check<F>(&self, check: F) -> Check
+    where
+        F: Future<Output = crate::storage::Result<()>>,
+    {
+        let started = Instant::now();
+        let result = tokio::time::timeout(self.timeout, check).await;
+        let latency_ms = started.elapsed().as_millis() as u64;
+
+        match result {
+            Ok(Ok(())) => Check {
+                status: Status::Up,
+                latency_ms,
+                error: None,
+            },
+            Ok(Err(error)) => Check {
+                status: Status::Down,
+                latency_ms,
+                error: Some(error.to_string()),
+            },
+            Err(_) => Check {
+                status: Status::Down,
+                latency_ms,
+                error: Some(format!("timed out after {:?}", self.timeout)),
+            },
+        }
+    }
+}
+
+/// Sends a `GET` request for `path` to the server listening on `host:port`, and returns the
+/// status it responded with. This is what `telemetry-server healthcheck` runs, so it only
+/// uses the standard library.
+pub fn probe(host: Option<&str>, port: u16, path: &str, timeout: Duration) -> Result<u16, String> {
+    // the server listens on every interface by default, which can't be connected to.
+    let host = match host {
+        None | Some("0.0.0.0") => "127.0.0.1",
+        Some("::") => "::1",
+        Some(host) => host,
+    };
+
+    let addr = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("unable to resolve {}:{}: {}", host, port, e))?
+        .next()
+        .ok_or_else(|| format!("{}:{} doesn't resolve to any address", host, port))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)
+        .map_err(|e| format!("unable to connect to {}: {}", addr, e))?;
+
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .and_then(|_| {
+            write!(
+                stream,
+                "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                path, addr
+            )
+        })
+        .map_err(|e| format!("unable to send request to {}: {}", addr, e))?;
+
+    // we only need the status line, i.e, `HTTP/1.1 200 OK`.
+    let mut status = String::new();
+    BufReader::new(stream)
+        .read_line(&mut status)
+        .map_err(|e| format!("unable to read response from {}: {}", addr, e))?;
+
+    status
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("unexpected response '{}'", status.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        time::Duration,
+    };
+
+    #[test]
+    fn probe_reads_the_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            // The request has to be read, otherwise closing the socket resets the
+            // connection before the client gets to read the response.
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                line.clear();
+            }
+
+            stream
+                .write_all(b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+        });
+
+        let status = super::probe(None, port, "/readyz", Duration::from_secs(2)).unwrap();
+        server.join().unwrap();
+        assert_eq!(status, 503);
+    }
+}
@@ -0,0 +1,164 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use validator::Validate;
+
+use crate::{
+    config::Config,
+    dead_letters::RejectionReason,
+    metrics::METRICS,
+    snowflake::Snowflake,
+    storage::{Event, EventStore},
+};
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct TrackBody {
+    #[validate(length(min = 1, max = 64))]
+    pub product: String,
+
+    #[validate(length(min = 1, max = 64))]
+    pub vendor: String,
+
+    #[validate(length(min = 1, max = 32))]
+    pub arch: String,
+
+    #[validate(length(min = 1, max = 64))]
+    pub os: String,
+
+    #[validate(length(min = 1, max = 64))]
+    pub version: String,
+
+    #[validate(length(min = 1, max = 64))]
+    pub distribution: String,
+
+    /// Anonymous identifier that the product generated for this installation.
+    #[validate(length(min = 1, max = 64))]
+    pub installation_id: Option<String>,
+    pub data: Value,
+}
+
+/// Represents why an event was rejected by the ingestion pipeline.
+#[derive(Debug)]
+pub struct Rejection {
+    pub reason: RejectionReason,
+    pub message: String,
+}
+
+impl Rejection {
+    fn new<S: ToString>(reason: RejectionReason, message: S) -> Rejection {
+        Rejection {
+            reason,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Deserializes and validates the raw request body into a [`TrackBody`].
+pub fn parse(body: &[u8]) -> Result<TrackBody, Rejection> {
+    let payload = tracing::info_span!("ingest.parse_json", body.size = body.len())
+        .in_scope(|| serde_json::from_slice::<TrackBody>(body))
+        .map_err(|e| Rejection::new(RejectionReason::InvalidJson, e))?;
+
+    tracing::info_span!("ingest.validate", product = %payload.product)
+        .in_scope(|| payload.validate())
+        .map_err(|e| Rejection::new(RejectionReason::ValidationFailed, e))?;
+
+    Ok(payload)
+}
+
+/// Enriches the payload with the time we received it at, which is what ends up
+/// being stored.
+pub fn enrich(id: u64, payload: TrackBody, received_at: DateTime<Utc>) -> Event {
+    Event {
+        id,
+        product: payload.product,
+        vendor: payload.vendor,
+        installation_id: payload.installation_id,
+        distribution: payload.distribution,
+        version: payload.version,
+        arch: payload.arch,
+        os: payload.os,
+        data: payload.data,
+        fired_at: received_at,
+    }
+}
+
+/// Runs the raw body through the whole ingestion pipeline, and returns the ID
+/// of the event that was stored.
+pub async fn ingest(
+    store: &dyn EventStore,
+    config: &Config,
+    snowflake: &mut Snowflake,
+    body: &[u8],
+    received_at: DateTime<Utc>,
+) -> Result<u64, Rejection> {
+    let id = snowflake.generate_at(received_at) as u64;
+    ingest_as(store, config, id, body, received_at).await
+}
+
+/// Runs the raw body through the whole ingestion pipeline, and stores it with
+/// an ID that was already issued, i.e, the one of a dead letter being replayed.
+#[tracing::instrument(name = "ingest", skip_all, fields(backend = store.name()))]
+pub async fn ingest_as(
+    store: &dyn EventStore,
+    config: &Config,
+    id: u64,
+    body: &[u8],
+    received_at: DateTime<Utc>,
+) -> Result<u64, Rejection> {
+    let payload = parse(body)?;
+    let product = payload.product.clone();
+
+    let started = Instant::now();
+    let result = store
+        .insert_events(vec![enrich(id, payload, received_at)])
+        .await;
+
+    METRICS.observe_insert(store.name(), result.is_ok(), started.elapsed());
+    result.map_err(|e| Rejection::new(RejectionReason::InsertFailed, e))?;
+
+    METRICS.observe_ingested(&product, config);
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dead_letters::RejectionReason;
+
+    #[test]
+    fn parse_rejects_invalid_bodies() {
+        let error = super::parse(b"{").unwrap_err();
+        assert_eq!(error.reason, RejectionReason::InvalidJson);
+
+        let error = super::parse(
+            br#"{"product":"","vendor":"Noelware","arch":"x86_64","os":"linux","version":"1.0.0","distribution":"docker","data":{}}"#,
+        )
+        .unwrap_err();
+
+        assert_eq!(error.reason, RejectionReason::ValidationFailed);
+
+        let payload = super::parse(
+            br#"{"product":"charted-server","vendor":"Noelware","arch":"x86_64","os":"linux","version":"1.0.0","distribution":"docker","data":{}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(payload.product, "charted-server");
+    }
+}
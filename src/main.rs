@@ -13,31 +13,125 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use config::Config;
+use std::time::Duration;
 
-use crate::{clickhouse::ClickHouse, telemetry::TelemetryServer};
+use clap::Parser;
+use cli::{Cli, Command, ConfigAction, MigrateAction};
+use config::{Config, DeadLetterConfig};
+
+use crate::{dead_letters::DeadLetters, telemetry::TelemetryServer};
 
 #[macro_use]
 extern crate log;
 extern crate actix_web;
 extern crate futures;
 
+mod adoption;
+mod analytics;
+mod cli;
 mod clickhouse;
+mod cohorts;
 mod config;
 mod constants;
+mod dashboard;
+mod dead_letters;
+mod funnels;
+mod health;
+mod ingest;
+mod memory;
+mod metrics;
+mod migrations;
+mod percentiles;
+mod replay;
 mod responses;
+mod retention;
 mod routes;
 mod setup_utils;
+mod shutdown;
 mod snowflake;
+mod sqlite;
+mod stats;
+mod storage;
 mod telemetry;
+mod transparency;
+
+/// Lists every problem with the configuration, and exits.
+fn invalid_config(errors: Vec<String>) -> ! {
+    eprintln!("the configuration is invalid:");
+    for error in errors {
+        eprintln!("  - {}", error);
+    }
+
+    std::process::exit(1);
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    Config::load();
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cli = Cli::parse();
+
+    // these don't log anything, so their output can be piped somewhere else.
+    match &cli.command {
+        Some(Command::Version) => {
+            println!(
+                "telemetry-server v{} ({}), built at {}",
+                constants::VERSION,
+                constants::COMMIT_HASH.trim(),
+                constants::BUILD_DATE
+            );
+
+            return Ok(());
+        }
+
+        Some(Command::Config { action }) => {
+            match action {
+                ConfigAction::Check => {
+                    Config::load(cli.sources()).unwrap_or_else(|e| invalid_config(e));
+                    println!("the configuration is valid");
+                }
+
+                ConfigAction::Print { effective } => {
+                    let rendered = Config::render(&cli.sources(), *effective)
+                        .unwrap_or_else(|e| invalid_config(e));
+
+                    print!("{}", rendered);
+                }
+            }
+
+            return Ok(());
+        }
+
+        Some(Command::Healthcheck { path, timeout }) => {
+            Config::load(cli.sources()).unwrap_or_else(|e| invalid_config(e));
+
+            let config = Config::get();
+            let status = health::probe(
+                config.host.as_deref(),
+                config.port.unwrap_or(1234),
+                path,
+                Duration::from_secs(*timeout),
+            );
+
+            match status {
+                Ok(status) if (200..300).contains(&status) => {
+                    println!("{} responded with {}", path, status);
+                    return Ok(());
+                }
 
+                Ok(status) => eprintln!("{} responded with {}", path, status),
+                Err(error) => eprintln!("{}", error),
+            }
+
+            std::process::exit(1);
+        }
+
+        _ => {}
+    }
+
+    Config::load(cli.sources()).unwrap_or_else(|e| invalid_config(e));
     let config = Config::get();
     setup_utils::setup_logging(config)?;
     setup_utils::setup_sentry(config)?;
+    setup_utils::setup_tracing(config)?;
 
     info!(
         "bootstrapping v{} ({}) of telemetry-server...",
@@ -45,9 +139,84 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         constants::COMMIT_HASH
     );
 
-    let clickhouse = ClickHouse::new(config.clickhouse.as_ref().unwrap());
-    let server = TelemetryServer::new(clickhouse.clone());
-    server.launch().await?;
+    let store = storage::from_config(config)?;
+    match cli.command {
+        Some(Command::Replay { dry_run, directory }) => {
+            let dead_letters = match directory {
+                Some(dir) => DeadLetters::new(
+                    store.clone(),
+                    Some(&DeadLetterConfig {
+                        persist_files: Some(true),
+                        directory: Some(dir.to_string_lossy().into_owned()),
+                        ..config.dead_letters.clone().unwrap_or_default()
+                    }),
+                ),
+                None => DeadLetters::new(store.clone(), config.dead_letters.as_ref()),
+            };
+
+            let reports = replay::replay(store.as_ref(), config, &dead_letters, dry_run).await;
+            for (reason, report) in reports {
+                println!(
+                    "{:<18} {} total, {} {}",
+                    reason,
+                    report.total,
+                    report.succeeded,
+                    if dry_run { "would succeed" } else { "replayed" }
+                );
+
+                for (code, count) in report.failed {
+                    println!("{:<18}   {} still failing with {}", "", count, code);
+                }
+            }
+
+            return Ok(());
+        }
+
+        Some(Command::Migrate { action }) => {
+            match action {
+                MigrateAction::Up { dry_run } => {
+                    let applied = migrations::up(store.as_ref(), dry_run).await?;
+                    if applied.is_empty() {
+                        println!("{} schema is up to date", store.name());
+                    }
+
+                    for migration in applied {
+                        println!(
+                            "{} {:04}_{}",
+                            if dry_run { "would apply" } else { "applied" },
+                            migration.version,
+                            migration.name
+                        );
+                    }
+                }
+
+                MigrateAction::Status => {
+                    for (migration, applied) in migrations::status(store.as_ref()).await? {
+                        println!(
+                            "{:04}_{:<32} {}",
+                            migration.version,
+                            migration.name,
+                            match applied {
+                                Some(applied) =>
+                                    format!("applied at {}", applied.applied_at.to_rfc3339()),
+                                None => "pending".into(),
+                            }
+                        );
+                    }
+                }
+            }
+
+            return Ok(());
+        }
+
+        // `serve`, or no subcommand at all; the others were handled above.
+        _ => {}
+    }
+
+    let server = TelemetryServer::new(store);
+    let result = server.launch().await;
 
-    Ok(())
+    // flushes the spans that weren't exported yet, which blocks until the exporter is done.
+    tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await?;
+    result
 }
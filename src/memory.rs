@@ -0,0 +1,196 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+
+use crate::{
+    analytics::{CountBucket, Counts, CountsQuery, EventQuery},
+    config::RetentionConfig,
+    dead_letters::{DeadLetter, DeadLetterQuery},
+    migrations::{AppliedMigration, Migration},
+    storage::{Event, EventStore, ProductStats, Result},
+};
+
+#[derive(Debug, Default)]
+struct State {
+    events: BTreeMap<u64, Event>,
+    dead_letters: BTreeMap<u64, DeadLetter>,
+}
+
+/// Storage backend that keeps everything in memory, which is useful for tests and
+/// demos. Everything is gone once the server stops!
+#[derive(Debug, Clone, Default)]
+pub struct Memory {
+    state: Arc<RwLock<State>>,
+    calls: Arc<AtomicUsize>,
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory::default()
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, State> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        self.state.read().unwrap()
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, State> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        self.state.write().unwrap()
+    }
+}
+
+#[async_trait]
+impl EventStore for Memory {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[]
+    }
+
+    async fn applied_migrations(&self) -> Result<Vec<AppliedMigration>> {
+        Ok(vec![])
+    }
+
+    async fn apply_migration(&self, _migration: &Migration) -> Result<()> {
+        Ok(())
+    }
+
+    async fn insert_events(&self, events: Vec<Event>) -> Result<()> {
+        let mut state = self.write();
+        state.events.extend(events.into_iter().map(|e| (e.id, e)));
+
+        Ok(())
+    }
+
+    async fn count_events(&self) -> Result<u64> {
+        Ok(self.read().events.len() as u64)
+    }
+
+    async fn events(&self, query: &EventQuery) -> Result<Vec<Event>> {
+        Ok(self
+            .read()
+            .events
+            .values()
+            .rev()
+            .filter(|e| query.matches(e))
+            .take(query.limit() as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn product_stats(&self) -> Result<Vec<ProductStats>> {
+        let mut stats = BTreeMap::<String, ProductStats>::new();
+        for event in self.read().events.values() {
+            let entry = stats
+                .entry(event.product.clone())
+                .or_insert_with(|| ProductStats {
+                    product: event.product.clone(),
+                    events: 0,
+                    oldest_event: None,
+                });
+
+            entry.events += 1;
+            if entry
+                .oldest_event
+                .is_none_or(|oldest| event.fired_at < oldest)
+            {
+                entry.oldest_event = Some(event.fired_at);
+            }
+        }
+
+        Ok(stats.into_values().collect())
+    }
+
+    async fn apply_retention(&self, retention: &RetentionConfig) -> Result<()> {
+        let now = Utc::now();
+        self.write().events.retain(|_, event| {
+            retention
+                .days_for(&event.product)
+                .is_none_or(|days| event.fired_at >= now - Duration::days(days as i64))
+        });
+
+        Ok(())
+    }
+
+    async fn apply_dead_letter_retention(&self, days: u32) -> Result<()> {
+        let cutoff = Utc::now() - Duration::days(days as i64);
+        self.write()
+            .dead_letters
+            .retain(|_, letter| letter.received_at >= cutoff);
+
+        Ok(())
+    }
+
+    async fn event_counts(&self, query: &CountsQuery) -> Result<Counts> {
+        let granularity = query.granularity();
+        let mut buckets = BTreeMap::<_, u64>::new();
+        for event in self.read().events.values().filter(|e| query.matches(e)) {
+            *buckets
+                .entry(granularity.truncate(event.fired_at))
+                .or_default() += 1;
+        }
+
+        Ok(Counts {
+            granularity,
+            source: "events",
+            buckets: buckets
+                .into_iter()
+                .map(|(time, count)| CountBucket { time, count })
+                .collect(),
+        })
+    }
+
+    async fn insert_dead_letter(&self, letter: &DeadLetter) -> Result<()> {
+        self.write().dead_letters.insert(letter.id, letter.clone());
+        Ok(())
+    }
+
+    async fn dead_letters(&self, query: &DeadLetterQuery) -> Result<Vec<DeadLetter>> {
+        Ok(self
+            .read()
+            .dead_letters
+            .values()
+            .rev()
+            .filter(|l| query.matches(l))
+            .take(query.limit() as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn dead_letter(&self, id: u64) -> Result<Option<DeadLetter>> {
+        Ok(self.read().dead_letters.get(&id).cloned())
+    }
+}
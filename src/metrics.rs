@@ -0,0 +1,185 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::config::Config;
+
+/// Label that events of products which aren't declared in `config.products` are counted
+/// under, so clients can't create a new series for every product name they send.
+pub const OTHER_PRODUCT: &str = "other";
+
+/// Metrics about the server itself, which are exposed on `/metrics` in the Prometheus
+/// exposition format.
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+    pub ingested_events: IntCounterVec,
+    pub insert_duration: HistogramVec,
+    pub pool_wait_duration: HistogramVec,
+    pub pool_acquisitions: IntCounterVec,
+}
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("telemetry".into()), None)
+            .expect("Unable to create metrics registry");
+
+        // 1ms to ~16s, which covers both cached responses and slow ClickHouse queries.
+        let buckets = exponential_buckets(0.001, 2.0, 15).unwrap();
+        let metrics = Metrics {
+            http_requests: IntCounterVec::new(
+                Opts::new(
+                    "http_requests_total",
+                    "How many HTTP requests were handled.",
+                ),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "How long HTTP requests took to handle.",
+                )
+                .buckets(buckets.clone()),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+            ingested_events: IntCounterVec::new(
+                Opts::new(
+                    "ingested_events_total",
+                    "How many events were stored for each product.",
+                ),
+                &["product"],
+            )
+            .unwrap(),
+            insert_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "storage_insert_duration_seconds",
+                    "How long inserting events into the storage backend took.",
+                )
+                .buckets(buckets.clone()),
+                &["backend", "result"],
+            )
+            .unwrap(),
+            pool_wait_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "storage_pool_wait_duration_seconds",
+                    "How long it took to get a connection from the pool.",
+                )
+                .buckets(buckets),
+                &["backend"],
+            )
+            .unwrap(),
+            pool_acquisitions: IntCounterVec::new(
+                Opts::new(
+                    "storage_pool_acquisitions_total",
+                    "How many connections were taken from the pool.",
+                ),
+                &["backend", "result"],
+            )
+            .unwrap(),
+            registry,
+        };
+
+        metrics
+            .registry
+            .register(Box::new(metrics.http_requests.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.http_request_duration.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.ingested_events.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.insert_duration.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.pool_wait_duration.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.pool_acquisitions.clone()))
+            .unwrap();
+
+        metrics
+    }
+
+    /// Records a request that was handled. `route` is the pattern that matched the request
+    /// (i.e, `/admin/dead-letters/{id}`) rather than its path, so there's one series per route.
+    pub fn observe_request(&self, method: &str, route: &str, status: StatusCode, took: Duration) {
+        let status = status.as_str();
+        self.http_requests
+            .with_label_values(&[method, route, status])
+            .inc();
+
+        self.http_request_duration
+            .with_label_values(&[method, route, status])
+            .observe(took.as_secs_f64());
+    }
+
+    /// Records an event that was stored.
+    pub fn observe_ingested(&self, product: &str, config: &Config) {
+        let declared = config
+            .products
+            .as_ref()
+            .is_some_and(|products| products.contains_key(product));
+
+        self.ingested_events
+            .with_label_values(&[if declared { product } else { OTHER_PRODUCT }])
+            .inc();
+    }
+
+    pub fn observe_insert(&self, backend: &str, success: bool, took: Duration) {
+        self.insert_duration
+            .with_label_values(&[backend, if success { "ok" } else { "error" }])
+            .observe(took.as_secs_f64());
+    }
+
+    pub fn observe_pool_wait(&self, backend: &str, success: bool, took: Duration) {
+        self.pool_wait_duration
+            .with_label_values(&[backend])
+            .observe(took.as_secs_f64());
+
+        self.pool_acquisitions
+            .with_label_values(&[backend, if success { "ok" } else { "error" }])
+            .inc();
+    }
+
+    /// Encodes every metric in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Unable to encode metrics");
+
+        String::from_utf8(buffer).expect("Metrics are always valid UTF-8")
+    }
+}
@@ -0,0 +1,271 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::storage::{EventStore, Result};
+
+/// Represents a schema migration that is embedded in the binary.
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Represents a single statement of a migration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    pub sql: String,
+
+    /// The statement is skipped if this table (`database.table`), or column
+    /// (`database.table.column`), exists. This is set with an `-- unless exists: <name>`
+    /// comment right before the statement, so statements that can't be re-run (like
+    /// renaming a table) don't fail when resuming a migration that was interrupted.
+    pub unless_exists: Option<&'static str>,
+}
+
+/// Represents a migration that was recorded in the `schema_migrations` table.
+#[derive(Debug, Clone, Serialize)]
+pub struct AppliedMigration {
+    pub version: u32,
+    pub name: String,
+    pub applied_at: DateTime<Utc>,
+}
+
+/// Migrations for the ClickHouse storage backend, in the order they are applied.
+pub const CLICKHOUSE: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_events",
+        sql: include_str!("../migrations/clickhouse/0001_create_events.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "create_dead_letters",
+        sql: include_str!("../migrations/clickhouse/0002_create_dead_letters.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "promote_envelope_fields",
+        sql: include_str!("../migrations/clickhouse/0003_promote_envelope_fields.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "add_installation_id",
+        sql: include_str!("../migrations/clickhouse/0004_add_installation_id.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "add_dead_letter_encoding",
+        sql: include_str!("../migrations/clickhouse/0005_add_dead_letter_encoding.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "create_rollups",
+        sql: include_str!("../migrations/clickhouse/0006_create_rollups.sql"),
+    },
+];
+
+/// Migrations for the SQLite storage backend, in the order they are applied.
+pub const SQLITE: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_events",
+        sql: include_str!("../migrations/sqlite/0001_create_events.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "create_dead_letters",
+        sql: include_str!("../migrations/sqlite/0002_create_dead_letters.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "promote_envelope_fields",
+        sql: include_str!("../migrations/sqlite/0003_promote_envelope_fields.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "add_installation_id",
+        sql: include_str!("../migrations/sqlite/0004_add_installation_id.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "add_dead_letter_encoding",
+        sql: include_str!("../migrations/sqlite/0005_add_dead_letter_encoding.sql"),
+    },
+];
+
+impl Migration {
+    /// Splits the migration into the statements it is made of, since ClickHouse
+    /// can only run one statement per query.
+    pub fn statements(&self) -> Vec<Statement> {
+        let mut statements = vec![];
+        let mut current = String::new();
+        let mut unless_exists = None;
+
+        for line in self.sql.lines() {
+            let trimmed = line.trim();
+            if let Some(name) = trimmed.strip_prefix("-- unless exists:") {
+                unless_exists = Some(name.trim());
+                continue;
+            }
+
+            if trimmed.is_empty() || trimmed.starts_with("--") {
+                continue;
+            }
+
+            current.push_str(line);
+            current.push('\n');
+
+            if trimmed.ends_with(';') {
+                statements.push(Statement {
+                    sql: current.trim().trim_end_matches(';').to_string(),
+                    unless_exists: unless_exists.take(),
+                });
+
+                current.clear();
+            }
+        }
+
+        if !current.trim().is_empty() {
+            statements.push(Statement {
+                sql: current.trim().to_string(),
+                unless_exists,
+            });
+        }
+
+        statements
+    }
+}
+
+/// Returns every migration the backend knows about, alongside when it was applied.
+pub async fn status(
+    store: &dyn EventStore,
+) -> Result<Vec<(&'static Migration, Option<AppliedMigration>)>> {
+    let applied = store.applied_migrations().await?;
+    for migration in applied.iter() {
+        if !store
+            .migrations()
+            .iter()
+            .any(|m| m.version == migration.version)
+        {
+            warn!(
+                "migration {} ({}) was applied, but isn't known by this version of telemetry-server",
+                migration.version, migration.name
+            );
+        }
+    }
+
+    Ok(store
+        .migrations()
+        .iter()
+        .map(|m| {
+            let applied = applied.iter().find(|a| a.version == m.version).cloned();
+            (m, applied)
+        })
+        .collect())
+}
+
+/// Returns the migrations that weren't applied yet.
+pub async fn pending(store: &dyn EventStore) -> Result<Vec<&'static Migration>> {
+    Ok(status(store)
+        .await?
+        .into_iter()
+        .filter(|(_, applied)| applied.is_none())
+        .map(|(m, _)| m)
+        .collect())
+}
+
+/// Applies every pending migration in order, and returns the ones that were (or
+/// would've been, if `dry_run` is set) applied.
+pub async fn up(store: &dyn EventStore, dry_run: bool) -> Result<Vec<&'static Migration>> {
+    let pending = pending(store).await?;
+    for migration in pending.iter() {
+        if dry_run {
+            info!(
+                "would apply migration {} ({})",
+                migration.version, migration.name
+            );
+
+            continue;
+        }
+
+        info!(
+            "applying migration {} ({})",
+            migration.version, migration.name
+        );
+
+        store.apply_migration(migration).await?;
+    }
+
+    Ok(pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CLICKHOUSE, SQLITE};
+
+    #[test]
+    fn migrations_are_ordered_and_never_drop_data() {
+        for migrations in [CLICKHOUSE, SQLITE] {
+            let mut last = 0;
+            for migration in migrations {
+                assert!(
+                    migration.version > last,
+                    "{} is out of order",
+                    migration.name
+                );
+                last = migration.version;
+
+                for statement in migration.statements() {
+                    let statement = statement.sql.to_uppercase();
+                    assert!(
+                        !statement.contains("DROP TABLE") && !statement.contains("TRUNCATE"),
+                        "migration {} drops data",
+                        migration.name
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn splits_statements() {
+        let statements = SQLITE[0].statements();
+        assert_eq!(statements.len(), 2);
+        assert!(statements[0]
+            .sql
+            .starts_with("CREATE TABLE IF NOT EXISTS events("));
+        assert!(statements[1].sql.starts_with("CREATE INDEX"));
+
+        let statements = CLICKHOUSE[2].statements();
+        assert!(statements[..3]
+            .iter()
+            .all(|s| s.unless_exists == Some("telemetry.events.Distribution")));
+
+        let rename = &statements[statements.len() - 2];
+        assert!(rename.sql.starts_with("RENAME TABLE"));
+        assert_eq!(rename.unless_exists, Some("telemetry.events_legacy"));
+
+        // copying the events that were stored during the backfill can always be re-run
+        let catch_up = statements.last().unwrap();
+        assert!(catch_up
+            .sql
+            .ends_with("WHERE ID NOT IN (SELECT ID FROM telemetry.events)"));
+        assert_eq!(catch_up.unless_exists, None);
+    }
+}
@@ -0,0 +1,251 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    analytics::{self, DataFilter, Dimension, EventQuery},
+    storage::Event,
+};
+
+/// Query for the percentiles endpoint, which computes statistics on a numeric value
+/// in the `data` object. Events that don't have a number at that path are left out.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct PercentileQuery {
+    pub path: String,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub os: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub group_by: Option<String>, // defaults to "product,version"
+    pub buckets: Option<usize>,   // defaults to 10, how many buckets the histogram has
+
+    #[serde(skip)]
+    pub keys: Vec<String>,
+
+    #[serde(skip)]
+    pub dimensions: Vec<Dimension>,
+
+    #[serde(skip)]
+    pub data: Vec<DataFilter>,
+}
+
+impl PercentileQuery {
+    /// Parses the `path` and `group_by` parameters.
+    pub fn validate(&mut self) -> Result<(), (&'static str, String)> {
+        self.keys = analytics::parse_path(&self.path).map_err(|e| ("INVALID_JSON_PATH", e))?;
+        self.dimensions =
+            Dimension::parse_list(self.group_by.as_deref().unwrap_or("product,version"))
+                .map_err(|e| ("INVALID_GROUP_BY", e))?;
+
+        Ok(())
+    }
+
+    pub fn buckets(&self) -> usize {
+        self.buckets.unwrap_or(10).clamp(1, 100)
+    }
+
+    pub fn events(&self) -> EventQuery {
+        EventQuery {
+            product: self.product.clone(),
+            version: self.version.clone(),
+            os: self.os.clone(),
+            from: self.from,
+            to: self.to,
+            data: self.data.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Computes the statistics out of the given events, for the backends that can't
+    /// do it themselves.
+    pub fn compute(&self, events: Vec<Event>) -> Percentiles {
+        let mut groups = BTreeMap::<Vec<Option<String>>, Vec<f64>>::new();
+        for event in events.iter() {
+            let Some(value) = analytics::lookup(&event.data, &self.keys).and_then(|v| v.as_f64())
+            else {
+                continue;
+            };
+
+            let group = self.dimensions.iter().map(|d| d.value(event)).collect();
+            groups.entry(group).or_default().push(value);
+        }
+
+        Percentiles {
+            path: self.path.clone(),
+            group_by: self.dimensions.iter().map(Dimension::name).collect(),
+            groups: groups
+                .into_iter()
+                .map(|(group, mut values)| {
+                    values.sort_by(f64::total_cmp);
+
+                    let samples = values.len() as u64;
+                    let (min, max) = (values[0], values[values.len() - 1]);
+                    let mut histogram = vec![0; self.buckets()];
+                    for value in values.iter() {
+                        histogram[self.bucket(*value, min, max)] += 1;
+                    }
+
+                    self.group(
+                        group,
+                        Summary {
+                            samples,
+                            mean: values.iter().sum::<f64>() / samples as f64,
+                            min,
+                            max,
+                            p50: quantile(&values, 0.5),
+                            p90: quantile(&values, 0.9),
+                            p99: quantile(&values, 0.99),
+                        },
+                        histogram,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns which histogram bucket the value falls in, where buckets are evenly
+    /// spread between the group's minimum and maximum.
+    pub fn bucket(&self, value: f64, min: f64, max: f64) -> usize {
+        if max <= min {
+            return 0;
+        }
+
+        let buckets = self.buckets();
+        (((value - min) / (max - min) * buckets as f64) as usize).min(buckets - 1)
+    }
+
+    /// Builds the statistics of a single group out of its summary and how many values
+    /// fell in each histogram bucket.
+    pub fn group(
+        &self,
+        group: Vec<Option<String>>,
+        summary: Summary,
+        histogram: Vec<u64>,
+    ) -> GroupPercentiles {
+        let width = (summary.max - summary.min) / histogram.len() as f64;
+        GroupPercentiles {
+            group: self
+                .dimensions
+                .iter()
+                .map(Dimension::name)
+                .zip(group)
+                .collect(),
+            histogram: histogram
+                .into_iter()
+                .enumerate()
+                .map(|(i, count)| HistogramBucket {
+                    lower: summary.min + width * i as f64,
+                    upper: summary.min + width * (i + 1) as f64,
+                    count,
+                })
+                .collect(),
+            summary,
+        }
+    }
+}
+
+/// Returns the quantile of the sorted values, interpolating between the closest ones.
+fn quantile(sorted: &[f64], level: f64) -> f64 {
+    let rank = level * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub samples: u64,
+    pub mean: f64,
+    pub min: f64,
+    pub max: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HistogramBucket {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct GroupPercentiles {
+    pub group: BTreeMap<String, Option<String>>,
+
+    #[serde(flatten)]
+    pub summary: Summary,
+    pub histogram: Vec<HistogramBucket>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Percentiles {
+    pub path: String,
+    pub group_by: Vec<String>,
+    pub groups: Vec<GroupPercentiles>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::PercentileQuery;
+    use crate::storage::Event;
+
+    #[test]
+    fn computes_percentiles_and_histograms() {
+        let event = |id: u64, version: &str, ms: f64| {
+            Event::test(id)
+                .with_version(version)
+                .with_data(json!({ "startup": { "ms": ms } }))
+        };
+
+        let mut query = PercentileQuery {
+            path: "startup.ms".into(),
+            group_by: Some("version".into()),
+            buckets: Some(4),
+            ..Default::default()
+        };
+
+        query.validate().unwrap();
+
+        let mut events = (0..=100)
+            .map(|i| event(i, "0.1.0", i as f64))
+            .collect::<Vec<_>>();
+
+        events.push(event(101, "0.2.0", 5.0));
+        let percentiles = query.compute(events);
+
+        let old = &percentiles.groups[0];
+        assert_eq!(old.group["version"], Some("0.1.0".into()));
+        assert_eq!(old.summary.samples, 101);
+        assert_eq!(old.summary.p50, 50.0);
+        assert_eq!(old.summary.p90, 90.0);
+        assert_eq!(old.summary.mean, 50.0);
+        assert_eq!(
+            old.histogram.iter().map(|b| b.count).collect::<Vec<_>>(),
+            vec![25, 25, 25, 26]
+        );
+
+        let new = &percentiles.groups[1];
+        assert_eq!(new.summary.p99, 5.0);
+        assert_eq!(new.histogram[0].count, 1);
+    }
+}
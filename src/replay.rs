@@ -0,0 +1,200 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    analytics::EventQuery,
+    config::Config,
+    dead_letters::DeadLetters,
+    ingest,
+    storage::{EventStore, Result},
+};
+
+/// Outcome of replaying every dead letter that was rejected for the same reason.
+#[derive(Debug, Default)]
+pub struct ReasonReport {
+    pub total: usize,
+    pub succeeded: usize,
+
+    /// Rejection codes of the bodies that still failed, with how many did.
+    pub failed: BTreeMap<&'static str, usize>,
+}
+
+/// Re-runs every persisted dead letter through the ingestion pipeline, keeping the time
+/// the server originally received it at. Events are stored with the ID of their dead
+/// letter, which the server issued from the same sequence as event IDs, so they can't
+/// collide with other events, and letters that were already replayed (i.e, when moving
+/// the file failed) are skipped. Successfully replayed bodies are moved out of the way,
+/// unless `dry_run` is set, in which case nothing is inserted at all.
+pub async fn replay(
+    store: &dyn EventStore,
+    config: &Config,
+    dead_letters: &DeadLetters,
+    dry_run: bool,
+) -> BTreeMap<&'static str, ReasonReport> {
+    if dead_letters.directory().is_none() {
+        warn!(
+            "persisting dead letters is disabled (config.dead_letters.persist_files), nothing to replay"
+        );
+        return BTreeMap::new();
+    }
+
+    let mut letters = dead_letters.read_files().await;
+    letters.sort_by_key(|(_, letter)| letter.id);
+
+    info!(
+        "replaying {} dead letters{}",
+        letters.len(),
+        if dry_run { " (dry run)" } else { "" }
+    );
+
+    let mut reports: BTreeMap<&'static str, ReasonReport> = BTreeMap::new();
+    for (path, letter) in letters {
+        let report = reports.entry(letter.reason.code()).or_default();
+        report.total += 1;
+
+        let body = match letter.body_bytes() {
+            Ok(body) => body,
+            Err(error) => {
+                error!("dead letter {} has a malformed body: {}", letter.id, error);
+                *report.failed.entry("MALFORMED_BODY").or_default() += 1;
+                continue;
+            }
+        };
+
+        let result = if dry_run {
+            ingest::parse(&body).map(|_| letter.id)
+        } else {
+            match is_stored(store, letter.id).await {
+                Ok(true) => {
+                    debug!("dead letter {} was already replayed", letter.id);
+                    Ok(letter.id)
+                }
+
+                Ok(false) => {
+                    ingest::ingest_as(store, config, letter.id, &body, letter.received_at).await
+                }
+                Err(error) => {
+                    error!(
+                        "unable to check if dead letter {} was already replayed: {}",
+                        letter.id, error
+                    );
+
+                    *report.failed.entry("INSERT_FAILED").or_default() += 1;
+                    continue;
+                }
+            }
+        };
+
+        match result {
+            Ok(id) => {
+                report.succeeded += 1;
+                if dry_run {
+                    continue;
+                }
+
+                debug!("replayed dead letter {} as event {}", letter.id, id);
+                if let Err(error) = dead_letters.mark_replayed(&path).await {
+                    error!(
+                        "dead letter {} was replayed, but couldn't be moved from {}: {}",
+                        letter.id,
+                        path.display(),
+                        error
+                    );
+                }
+            }
+
+            Err(rejection) => {
+                debug!(
+                    "dead letter {} was rejected again ({}): {}",
+                    letter.id,
+                    rejection.reason.code(),
+                    rejection.message
+                );
+
+                *report.failed.entry(rejection.reason.code()).or_default() += 1;
+            }
+        }
+    }
+
+    reports
+}
+
+/// Checks if an event with the given ID was already stored.
+async fn is_stored(store: &dyn EventStore, id: u64) -> Result<bool> {
+    let events = store
+        .events(&EventQuery {
+            before: Some(id + 1),
+            limit: Some(1),
+            ..Default::default()
+        })
+        .await?;
+
+    Ok(events.first().map(|e| e.id) == Some(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::Utc;
+
+    use crate::{
+        analytics::EventQuery,
+        config::{Config, DeadLetterConfig},
+        dead_letters::{DeadLetter, DeadLetters, RejectionReason},
+        memory::Memory,
+        storage::EventStore,
+    };
+
+    #[tokio::test]
+    async fn replayed_events_keep_their_dead_letter_id() {
+        let directory =
+            std::env::temp_dir().join(format!("telemetry-replay-{}", std::process::id()));
+        let config: Config = toml::from_str("").unwrap();
+        let store: Arc<dyn EventStore> = Arc::new(Memory::new());
+        let dead_letters = DeadLetters::new(
+            store.clone(),
+            Some(&DeadLetterConfig {
+                persist_files: Some(true),
+                directory: Some(directory.to_string_lossy().into_owned()),
+                ..Default::default()
+            }),
+        );
+
+        let letter = DeadLetter::new(
+            42,
+            RejectionReason::InsertFailed,
+            br#"{"product":"charted-server","vendor":"Noelware","arch":"x86_64","os":"linux","version":"0.1.0","distribution":"docker","data":{}}"#,
+            "connection refused".into(),
+            Utc::now(),
+        );
+
+        // The second time simulates a replay that stored the event, but couldn't
+        // move the file out of the way.
+        for _ in 0..2 {
+            dead_letters.store(letter.clone()).await;
+            let reports = super::replay(store.as_ref(), &config, &dead_letters, false).await;
+            assert_eq!(reports["INSERT_FAILED"].succeeded, 1);
+        }
+
+        let events = store.events(&EventQuery::default()).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].id, 42);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{sync::Arc, time::Duration as StdDuration};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::{
+    config::{RetentionConfig, SharedConfig},
+    dead_letters::DeadLetters,
+    storage::{EventStore, ProductStats, Result},
+};
+
+/// How often the retention is re-applied on the storage backend. ClickHouse deletes
+/// expired events by itself once the TTL is set, but the other backends don't.
+pub const INTERVAL: StdDuration = StdDuration::from_secs(60 * 60);
+
+/// Represents how long a product's events are kept for, and what is currently stored.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProductRetention {
+    pub product: String,
+    pub retention_days: Option<u32>,
+    pub events: u64,
+    pub oldest_event: Option<DateTime<Utc>>,
+
+    /// Events fired before this are expected to be deleted.
+    pub cutoff: Option<DateTime<Utc>>,
+
+    /// If the oldest event is past the cutoff. ClickHouse deletes expired rows when
+    /// it merges parts, so this can be `true` for a little while.
+    pub overdue: bool,
+}
+
+/// Reports the retention of every product that has stored events or a configured
+/// retention.
+pub async fn report(
+    store: &dyn EventStore,
+    retention: &RetentionConfig,
+) -> Result<Vec<ProductRetention>> {
+    let now = Utc::now();
+    let mut stats = store.product_stats().await?;
+
+    for product in retention.products.iter().flat_map(|p| p.keys()) {
+        if !stats.iter().any(|s| &s.product == product) {
+            stats.push(ProductStats {
+                product: product.clone(),
+                events: 0,
+                oldest_event: None,
+            });
+        }
+    }
+
+    stats.sort_by(|a, b| a.product.cmp(&b.product));
+    Ok(stats
+        .into_iter()
+        .map(|stats| {
+            let retention_days = retention.days_for(&stats.product);
+            let cutoff = retention_days.map(|days| now - Duration::days(days as i64));
+
+            ProductRetention {
+                overdue: matches!((stats.oldest_event, cutoff), (Some(oldest), Some(cutoff)) if oldest < cutoff),
+                product: stats.product,
+                retention_days,
+                events: stats.events,
+                oldest_event: stats.oldest_event,
+                cutoff,
+            }
+        })
+        .collect())
+}
+
+/// Applies the retention of the current configuration on the storage backend, and
+/// on the dead letters.
+pub async fn apply(store: &dyn EventStore, dead_letters: &DeadLetters, config: &SharedConfig) {
+    let config = config.get();
+    let retention = config.retention.clone().unwrap_or_default();
+    if let Err(error) = store.apply_retention(&retention).await {
+        error!("unable to apply retention on {}: {}", store.name(), error);
+    }
+
+    let days = config
+        .dead_letters
+        .as_ref()
+        .and_then(|c| c.retention_days)
+        .unwrap_or(30);
+
+    dead_letters.apply_retention(days).await;
+}
+
+/// Re-applies the retention every [`INTERVAL`] until the server stops, with the
+/// configuration at that time so that reloading it takes effect. The first run is
+/// one interval from now, since the server applies it once before starting.
+pub fn spawn(store: Arc<dyn EventStore>, dead_letters: DeadLetters, config: SharedConfig) {
+    tokio::spawn(async move {
+        let start = tokio::time::Instant::now() + INTERVAL;
+        let mut interval = tokio::time::interval_at(start, INTERVAL);
+        loop {
+            interval.tick().await;
+            apply(store.as_ref(), &dead_letters, &config).await;
+        }
+    });
+}
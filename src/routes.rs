@@ -13,19 +13,32 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::time::SystemTime;
+use std::{net::IpAddr, time::SystemTime};
 
-use actix_web::{web, HttpResponse, Responder};
-use chrono::{DateTime, Utc};
-use clickhouse_rs::Block;
+use actix_web::{
+    cookie::{time::Duration as CookieDuration, Cookie, SameSite},
+    http::header::{AUTHORIZATION, LOCATION},
+    web, HttpRequest, HttpResponse, Responder,
+};
+use chrono::{DateTime, Duration, Utc};
 use futures::StreamExt;
-use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde::Serialize;
 
 use crate::{
-    clickhouse::ClickHouse,
+    adoption::AdoptionQuery,
+    analytics::{AggregateQuery, CountsQuery, DataFilter, EventPage, EventQuery},
+    cohorts::CohortQuery,
+    dashboard::{self, LoginForm, Overview, OverviewQuery},
+    dead_letters::{DeadLetter, DeadLetterQuery, RejectionReason},
+    funnels::FunnelQuery,
+    health::Status,
+    ingest,
+    metrics::METRICS,
+    percentiles::PercentileQuery,
     responses::{self, respond, ApiResponse, Empty},
+    retention,
     telemetry::TelemetryServer,
+    transparency,
 };
 
 #[derive(Serialize, Debug)]
@@ -33,21 +46,25 @@ struct MainResponse {
     message: String,
 }
 
+#[derive(Serialize, Debug)]
+struct LivenessResponse {
+    status: Status,
+}
+
 #[derive(Serialize, Debug)]
 struct StatsResponse {
     db_calls: usize,
     events_emitted: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct TrackBody {
-    product: String,
-    vendor: String,
-    arch: String,
-    os: String,
-    version: String,
-    distribution: String,
-    data: Value,
+#[derive(Serialize, Debug)]
+struct DeadLetterSummary {
+    id: u64,
+    reason: RejectionReason,
+    product: Option<String>,
+    message: String,
+    body_size: usize,
+    received_at: DateTime<Utc>,
 }
 
 pub async fn home() -> impl Responder {
@@ -56,32 +73,63 @@ pub async fn home() -> impl Responder {
     }))
 }
 
-pub async fn stats(data: web::Data<TelemetryServer>) -> impl Responder {
-    let clickhouse = data.clickhouse.clone();
-    let calls = ClickHouse::calls();
-
-    let events_emitted = clickhouse
-        .query("SELECT COUNT(*) FROM telemetry.events", |block| {
-            block.get::<u64, _>(0, 0).unwrap_or(0)
-        })
-        .await;
+/// Only checks if the process is able to handle requests, so orchestrators don't restart
+/// the server when one of its dependencies is down.
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(respond(LivenessResponse { status: Status::Up }))
+}
 
-    if events_emitted.is_err() {
-        return HttpResponse::InternalServerError().json(responses::error(
-            "UNKNOWN_ERROR",
-            "Unknown exception had occurred while collecting statistics. :(",
+pub async fn readyz(data: web::Data<TelemetryServer>) -> HttpResponse {
+    if data.shutdown.is_shutting_down() {
+        return HttpResponse::ServiceUnavailable().json(responses::error(
+            "SHUTTING_DOWN",
+            "The server is shutting down.",
         ));
     }
 
-    HttpResponse::Ok().json(respond(StatsResponse {
-        db_calls: calls,
-        events_emitted: events_emitted.unwrap(),
-    }))
+    let readiness = data.health.readiness().await;
+    if readiness.ready {
+        return HttpResponse::Ok().json(respond(readiness));
+    }
+
+    HttpResponse::ServiceUnavailable().json(ApiResponse {
+        success: false,
+        data: Some(readiness),
+        errors: Some(vec![responses::Error::new(
+            "NOT_READY",
+            "One or more dependencies are unavailable.",
+        )]),
+    })
+}
+
+/// Reports how many events are stored, which is counted at most once every
+/// [`crate::stats::CACHE_FOR`] so that frequent requests don't count every event.
+pub async fn stats(data: web::Data<TelemetryServer>) -> impl Responder {
+    match data.event_count.get().await {
+        Ok(events_emitted) => HttpResponse::Ok().json(respond(StatsResponse {
+            db_calls: data.store.calls(),
+            events_emitted,
+        })),
+        Err(error) => {
+            error!("unable to count stored events: {}", error);
+            HttpResponse::InternalServerError().json(responses::error(
+                "UNKNOWN_ERROR",
+                "Unknown exception had occurred while collecting statistics. :(",
+            ))
+        }
+    }
+}
+
+pub async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(METRICS.encode())
 }
 
 // But, how can we not forge data? Well, I will tell you.
 
 pub async fn send(
+    req: HttpRequest,
     mut data_payload: web::Payload,
     data: web::Data<TelemetryServer>,
 ) -> Result<HttpResponse, actix_web::Error> {
@@ -102,36 +150,1138 @@ pub async fn send(
         body.extend_from_slice(&chunk);
     }
 
-    let payload = serde_json::from_slice::<TrackBody>(&body)?;
-    let clickhouse = data.clickhouse.clone();
     let now = SystemTime::now();
     let now_in_utc: DateTime<Utc> = now.into();
 
-    let payload_to_ch = json!({
-        "distribution": payload.distribution,
-        "version": payload.version,
-        "arch": payload.arch,
-        "os": payload.os,
-        "data": payload.data,
-        "fired_at": now_in_utc.to_rfc3339()
-    });
+    let in_flight = match data.shutdown.track(format!(
+        "event received at {} ({} bytes)",
+        now_in_utc.to_rfc3339(),
+        body.len()
+    )) {
+        Some(in_flight) => in_flight,
+        None => {
+            return Ok(HttpResponse::ServiceUnavailable().json(responses::error(
+                "SHUTTING_DOWN",
+                "The server is shutting down, try again later.",
+            )))
+        }
+    };
 
+    let config = data.config.get();
     let mut snowflake = data.snowflake.clone();
-    let id = snowflake.generate();
-    let block = Block::new()
-        .column("Data", vec![serde_json::to_string(&payload_to_ch).unwrap()])
-        .column("ID", vec![id as u64])
-        .column("Product", vec![payload.product])
-        .column("Vendor", vec![payload.vendor]);
-
-    clickhouse
-        .insert("events", block.clone())
-        .await
-        .expect("Unable to insert into ClickHouse");
+    let result = ingest::ingest(
+        data.store.as_ref(),
+        &config,
+        &mut snowflake,
+        &body,
+        now_in_utc,
+    )
+    .await;
+
+    if let Err(rejection) = result {
+        let (mut res, message) = match rejection.reason {
+            RejectionReason::InvalidJson => (HttpResponse::BadRequest(), rejection.message.clone()),
+            RejectionReason::ValidationFailed => (
+                HttpResponse::UnprocessableEntity(),
+                rejection.message.clone(),
+            ),
+            RejectionReason::InsertFailed => (
+                HttpResponse::InternalServerError(),
+                "Unable to store telemetry event, it was kept for inspection.".to_string(),
+            ),
+        };
+
+        reject(
+            &data,
+            req.peer_addr().map(|addr| addr.ip()),
+            rejection.reason,
+            &body,
+            rejection.message,
+            now_in_utc,
+        )
+        .await;
+
+        // the event is only done with once its dead letter was written, so shutting down
+        // waits for it too.
+        in_flight.finish();
+        return Ok(res.json(responses::error(rejection.reason.code(), message.as_str())));
+    }
 
+    in_flight.finish();
     Ok(HttpResponse::Created().json(ApiResponse::<Empty> {
         success: true,
         data: None,
         errors: None,
     }))
 }
+
+async fn reject(
+    data: &web::Data<TelemetryServer>,
+    client: Option<IpAddr>,
+    reason: RejectionReason,
+    body: &[u8],
+    message: String,
+    received_at: DateTime<Utc>,
+) {
+    if !data.dead_letters.admit(client, reason) {
+        debug!(
+            "not keeping rejected event ({}) from {:?}, it sent too many of them: {}",
+            reason.code(),
+            client,
+            message
+        );
+
+        return;
+    }
+
+    let mut snowflake = data.snowflake.clone();
+    let id = snowflake.generate() as u64;
+
+    data.dead_letters
+        .store(DeadLetter::new(id, reason, body, message, received_at))
+        .await;
+}
+
+/// Checks if the request has the configured admin token in the `Authorization` header, and
+/// returns the response to send back if it doesn't.
+fn authorize(req: &HttpRequest, data: &TelemetryServer) -> Option<HttpResponse> {
+    let config = data.config.get();
+    let token = match &config.admin_token {
+        Some(token) => token,
+        None => {
+            return Some(HttpResponse::Forbidden().json(responses::error(
+                "ADMIN_DISABLED",
+                "Admin endpoints are disabled since no admin token was configured.",
+            )))
+        }
+    };
+
+    let header = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.strip_prefix("Bearer ").unwrap_or(h));
+
+    match header {
+        Some(value) if token.matches(value) => None,
+        _ => Some(HttpResponse::Unauthorized().json(responses::error(
+            "UNAUTHORIZED",
+            "Missing or invalid admin token.",
+        ))),
+    }
+}
+
+pub async fn reload_config(req: HttpRequest, data: web::Data<TelemetryServer>) -> HttpResponse {
+    if let Some(res) = authorize(&req, &data) {
+        return res;
+    }
+
+    match data.reload() {
+        Ok(reload) => HttpResponse::Ok().json(respond(reload)),
+        Err(errors) => HttpResponse::BadRequest().json(ApiResponse::<Empty> {
+            success: false,
+            data: None,
+            errors: Some(
+                errors
+                    .iter()
+                    .map(|e| responses::Error::new("INVALID_CONFIG", e))
+                    .collect(),
+            ),
+        }),
+    }
+}
+
+pub async fn dead_letters(
+    req: HttpRequest,
+    query: web::Query<DeadLetterQuery>,
+    data: web::Data<TelemetryServer>,
+) -> HttpResponse {
+    if let Some(res) = authorize(&req, &data) {
+        return res;
+    }
+
+    let letters = data
+        .dead_letters
+        .list(&query)
+        .await
+        .into_iter()
+        .map(|letter| DeadLetterSummary {
+            body_size: letter
+                .body_bytes()
+                .map_or(letter.body.len(), |body| body.len()),
+            id: letter.id,
+            reason: letter.reason,
+            product: letter.product,
+            message: letter.message,
+            received_at: letter.received_at,
+        })
+        .collect::<Vec<_>>();
+
+    HttpResponse::Ok().json(respond(letters))
+}
+
+pub async fn dead_letter(
+    req: HttpRequest,
+    id: web::Path<u64>,
+    data: web::Data<TelemetryServer>,
+) -> HttpResponse {
+    if let Some(res) = authorize(&req, &data) {
+        return res;
+    }
+
+    match data.dead_letters.get(id.into_inner()).await {
+        Some(letter) => HttpResponse::Ok().json(respond(letter)),
+        None => HttpResponse::NotFound().json(responses::error(
+            "UNKNOWN_DEAD_LETTER",
+            "Dead letter with that ID doesn't exist.",
+        )),
+    }
+}
+
+pub async fn retention(req: HttpRequest, data: web::Data<TelemetryServer>) -> HttpResponse {
+    if let Some(res) = authorize(&req, &data) {
+        return res;
+    }
+
+    let config = data.config.get().retention.clone().unwrap_or_default();
+    match retention::report(data.store.as_ref(), &config).await {
+        Ok(report) => HttpResponse::Ok().json(respond(report)),
+        Err(error) => {
+            error!("unable to collect retention report: {}", error);
+            HttpResponse::InternalServerError().json(responses::error(
+                "UNKNOWN_ERROR",
+                "Unknown exception had occurred while collecting the retention report. :(",
+            ))
+        }
+    }
+}
+
+pub async fn events(
+    req: HttpRequest,
+    query: web::Query<EventQuery>,
+    data: web::Data<TelemetryServer>,
+) -> HttpResponse {
+    if let Some(res) = authorize(&req, &data) {
+        return res;
+    }
+
+    let mut query = query.into_inner();
+    query.before = match query.cursor.as_deref().map(str::parse::<u64>).transpose() {
+        Ok(before) => before,
+        Err(_) => {
+            return HttpResponse::BadRequest()
+                .json(responses::error("INVALID_CURSOR", "Cursor is not valid."))
+        }
+    };
+
+    query.data = match data_filters(&req) {
+        Ok(filters) => filters,
+        Err(message) => {
+            return HttpResponse::BadRequest()
+                .json(responses::error("INVALID_JSON_PATH", message.as_str()))
+        }
+    };
+
+    match data.store.events(&query).await {
+        Ok(events) => {
+            let next_cursor = if events.len() as u64 == query.limit() {
+                events.last().map(|event| event.id.to_string())
+            } else {
+                None
+            };
+
+            HttpResponse::Ok().json(respond(EventPage {
+                events,
+                next_cursor,
+            }))
+        }
+
+        Err(error) => {
+            error!("unable to list events: {}", error);
+            HttpResponse::InternalServerError().json(responses::error(
+                "UNKNOWN_ERROR",
+                "Unknown exception had occurred while listing events. :(",
+            ))
+        }
+    }
+}
+
+pub async fn aggregate(
+    req: HttpRequest,
+    query: web::Query<AggregateQuery>,
+    data: web::Data<TelemetryServer>,
+) -> HttpResponse {
+    if let Some(res) = authorize(&req, &data) {
+        return res;
+    }
+
+    let mut query = query.into_inner();
+    if let Err((code, message)) = query.validate() {
+        return HttpResponse::BadRequest().json(responses::error(code, message.as_str()));
+    }
+
+    query.data = match data_filters(&req) {
+        Ok(filters) => filters,
+        Err(message) => {
+            return HttpResponse::BadRequest()
+                .json(responses::error("INVALID_JSON_PATH", message.as_str()))
+        }
+    };
+
+    match data.store.aggregate(&query).await {
+        Ok(aggregation) => HttpResponse::Ok().json(respond(aggregation)),
+        Err(error) => {
+            error!("unable to aggregate events: {}", error);
+            HttpResponse::InternalServerError().json(responses::error(
+                "UNKNOWN_ERROR",
+                "Unknown exception had occurred while aggregating events. :(",
+            ))
+        }
+    }
+}
+
+pub async fn versions(
+    req: HttpRequest,
+    query: web::Query<AdoptionQuery>,
+    data: web::Data<TelemetryServer>,
+) -> HttpResponse {
+    if let Some(res) = authorize(&req, &data) {
+        return res;
+    }
+
+    if let Err(message) = query.tz() {
+        return HttpResponse::BadRequest()
+            .json(responses::error("INVALID_TIMEZONE", message.as_str()));
+    }
+
+    match data.store.version_adoption(&query).await {
+        Ok(adoption) => HttpResponse::Ok().json(respond(adoption)),
+        Err(error) => {
+            error!("unable to compute version adoption: {}", error);
+            HttpResponse::InternalServerError().json(responses::error(
+                "UNKNOWN_ERROR",
+                "Unknown exception had occurred while computing version adoption. :(",
+            ))
+        }
+    }
+}
+
+pub async fn cohorts(
+    req: HttpRequest,
+    query: web::Query<CohortQuery>,
+    data: web::Data<TelemetryServer>,
+) -> HttpResponse {
+    if let Some(res) = authorize(&req, &data) {
+        return res;
+    }
+
+    let mut query = query.into_inner();
+    if let Err(message) = query.tz() {
+        return HttpResponse::BadRequest()
+            .json(responses::error("INVALID_TIMEZONE", message.as_str()));
+    }
+
+    query.data = match data_filters(&req) {
+        Ok(filters) => filters,
+        Err(message) => {
+            return HttpResponse::BadRequest()
+                .json(responses::error("INVALID_JSON_PATH", message.as_str()))
+        }
+    };
+
+    match data.store.cohorts(&query).await {
+        Ok(matrix) => HttpResponse::Ok().json(respond(matrix)),
+        Err(error) => {
+            error!("unable to compute cohorts: {}", error);
+            HttpResponse::InternalServerError().json(responses::error(
+                "UNKNOWN_ERROR",
+                "Unknown exception had occurred while computing cohorts. :(",
+            ))
+        }
+    }
+}
+
+pub async fn funnel(
+    req: HttpRequest,
+    query: web::Json<FunnelQuery>,
+    data: web::Data<TelemetryServer>,
+) -> HttpResponse {
+    if let Some(res) = authorize(&req, &data) {
+        return res;
+    }
+
+    let mut query = query.into_inner();
+    if let Err(message) = query.validate() {
+        return HttpResponse::BadRequest()
+            .json(responses::error("INVALID_FUNNEL", message.as_str()));
+    }
+
+    match data.store.funnel(&query).await {
+        Ok(funnel) => HttpResponse::Ok().json(respond(funnel)),
+        Err(error) => {
+            error!("unable to compute funnel: {}", error);
+            HttpResponse::InternalServerError().json(responses::error(
+                "UNKNOWN_ERROR",
+                "Unknown exception had occurred while computing the funnel. :(",
+            ))
+        }
+    }
+}
+
+pub async fn percentiles(
+    req: HttpRequest,
+    query: web::Query<PercentileQuery>,
+    data: web::Data<TelemetryServer>,
+) -> HttpResponse {
+    if let Some(res) = authorize(&req, &data) {
+        return res;
+    }
+
+    let mut query = query.into_inner();
+    if let Err((code, message)) = query.validate() {
+        return HttpResponse::BadRequest().json(responses::error(code, message.as_str()));
+    }
+
+    query.data = match data_filters(&req) {
+        Ok(filters) => filters,
+        Err(message) => {
+            return HttpResponse::BadRequest()
+                .json(responses::error("INVALID_JSON_PATH", message.as_str()))
+        }
+    };
+
+    match data.store.percentiles(&query).await {
+        Ok(percentiles) => HttpResponse::Ok().json(respond(percentiles)),
+        Err(error) => {
+            error!("unable to compute percentiles: {}", error);
+            HttpResponse::InternalServerError().json(responses::error(
+                "UNKNOWN_ERROR",
+                "Unknown exception had occurred while computing percentiles. :(",
+            ))
+        }
+    }
+}
+
+pub async fn transparency(data: web::Data<TelemetryServer>) -> HttpResponse {
+    match data.transparency.get(&data.config.get()).await {
+        Ok(report) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(transparency::render(&report)),
+        Err(error) => {
+            error!("unable to generate the transparency report: {}", error);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn transparency_json(data: web::Data<TelemetryServer>) -> HttpResponse {
+    match data.transparency.get(&data.config.get()).await {
+        Ok(report) => HttpResponse::Ok().json(respond(report)),
+        Err(error) => {
+            error!("unable to generate the transparency report: {}", error);
+            HttpResponse::InternalServerError().json(responses::error(
+                "UNKNOWN_ERROR",
+                "Unknown exception had occurred while generating the transparency report. :(",
+            ))
+        }
+    }
+}
+
+fn dashboard_disabled() -> HttpResponse {
+    HttpResponse::NotFound().json(responses::error(
+        "DASHBOARD_DISABLED",
+        "The dashboard is disabled since no access token was configured.",
+    ))
+}
+
+pub async fn dashboard(req: HttpRequest, data: web::Data<TelemetryServer>) -> HttpResponse {
+    let config = data.config.get();
+    let token = match dashboard::token(config.dashboard.as_ref()) {
+        Some(token) => token,
+        None => return dashboard_disabled(),
+    };
+
+    let page = if dashboard::is_authorized(&req, token) {
+        dashboard::INDEX
+    } else {
+        dashboard::LOGIN
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(page)
+}
+
+pub async fn dashboard_login(
+    form: web::Form<LoginForm>,
+    data: web::Data<TelemetryServer>,
+) -> HttpResponse {
+    let config = data.config.get();
+    let token = match dashboard::token(config.dashboard.as_ref()) {
+        Some(token) => token,
+        None => return dashboard_disabled(),
+    };
+
+    if !token.matches(&form.token) {
+        return HttpResponse::Unauthorized()
+            .content_type("text/html; charset=utf-8")
+            .body(dashboard::LOGIN);
+    }
+
+    let expires_at = Utc::now() + Duration::days(dashboard::SESSION_DAYS);
+    let cookie = Cookie::build(dashboard::COOKIE, dashboard::session(token, expires_at))
+        .path("/dashboard")
+        .http_only(true)
+        .secure(
+            config
+                .dashboard
+                .as_ref()
+                .and_then(|d| d.secure_cookie)
+                .unwrap_or(true),
+        )
+        .same_site(SameSite::Strict)
+        .max_age(CookieDuration::days(dashboard::SESSION_DAYS))
+        .finish();
+
+    HttpResponse::SeeOther()
+        .cookie(cookie)
+        .insert_header((LOCATION, "/dashboard"))
+        .finish()
+}
+
+pub async fn dashboard_logout() -> HttpResponse {
+    let mut cookie = Cookie::build(dashboard::COOKIE, "")
+        .path("/dashboard")
+        .finish();
+
+    cookie.make_removal();
+    HttpResponse::SeeOther()
+        .cookie(cookie)
+        .insert_header((LOCATION, "/dashboard"))
+        .finish()
+}
+
+pub async fn dashboard_asset(
+    req: HttpRequest,
+    name: web::Path<String>,
+    data: web::Data<TelemetryServer>,
+) -> HttpResponse {
+    let config = data.config.get();
+    let token = match dashboard::token(config.dashboard.as_ref()) {
+        Some(token) => token,
+        None => return dashboard_disabled(),
+    };
+
+    match dashboard::asset(&name) {
+        Some(asset) if asset.public || dashboard::is_authorized(&req, token) => HttpResponse::Ok()
+            .content_type(asset.content_type)
+            .body(asset.body),
+        Some(_) => HttpResponse::Unauthorized().finish(),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+pub async fn dashboard_overview(
+    req: HttpRequest,
+    query: web::Query<OverviewQuery>,
+    data: web::Data<TelemetryServer>,
+) -> HttpResponse {
+    let config = data.config.get();
+    let token = match dashboard::token(config.dashboard.as_ref()) {
+        Some(token) => token,
+        None => return dashboard_disabled(),
+    };
+
+    if !dashboard::is_authorized(&req, token) {
+        return HttpResponse::Unauthorized().json(responses::error(
+            "UNAUTHORIZED",
+            "Missing or invalid dashboard token.",
+        ));
+    }
+
+    let query = query.aggregate(Utc::now());
+    match data.store.aggregate(&query).await {
+        Ok(aggregation) => HttpResponse::Ok().json(respond(Overview::new(&query, aggregation))),
+        Err(error) => {
+            error!("unable to compute the dashboard overview: {}", error);
+            HttpResponse::InternalServerError().json(responses::error(
+                "UNKNOWN_ERROR",
+                "Unknown exception had occurred while computing the overview. :(",
+            ))
+        }
+    }
+}
+
+/// Collects the `data.<path>=<value>` filters from the query string.
+fn data_filters(req: &HttpRequest) -> Result<Vec<DataFilter>, String> {
+    let pairs = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map(|q| q.into_inner())
+        .unwrap_or_default();
+
+    DataFilter::from_pairs(&pairs)
+}
+
+pub async fn counts(
+    req: HttpRequest,
+    query: web::Query<CountsQuery>,
+    data: web::Data<TelemetryServer>,
+) -> HttpResponse {
+    if let Some(res) = authorize(&req, &data) {
+        return res;
+    }
+
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from >= to {
+            return HttpResponse::BadRequest().json(responses::error(
+                "INVALID_RANGE",
+                "`from` must be before `to`.",
+            ));
+        }
+    }
+
+    match data.store.event_counts(&query).await {
+        Ok(counts) => HttpResponse::Ok().json(respond(counts)),
+        Err(error) => {
+            error!("unable to count events: {}", error);
+            HttpResponse::InternalServerError().json(responses::error(
+                "UNKNOWN_ERROR",
+                "Unknown exception had occurred while counting events. :(",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{
+        http::{header::AUTHORIZATION, StatusCode},
+        test,
+        web::Data,
+        App,
+    };
+    use chrono::{Duration, Utc};
+    use serde_json::{json, Value};
+
+    use crate::{config::Config, memory::Memory, storage::Event, telemetry::TelemetryServer};
+
+    const EVENT: &str = r#"{"product":"charted-server","vendor":"Noelware","arch":"x86_64","os":"linux","version":"0.1.0","distribution":"docker","data":{"hello":"world"}}"#;
+
+    const CONFIG: &str = r#"
+admin_token = "owo"
+
+[dead_letters]
+persist_files = false
+
+[dashboard]
+token = "uwu"
+
+[products.charted-server]
+description = "Helm chart registry."
+
+[products.charted-server.fields]
+"cluster.nodes" = "How many nodes the cluster has, so we know how big installations get."
+
+[retention]
+default_days = 30
+
+[retention.products]
+ume = 7
+"#;
+
+    fn server() -> TelemetryServer {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        TelemetryServer::with_config(config, Arc::new(Memory::new()))
+    }
+
+    macro_rules! app {
+        ($server:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(Data::new($server.clone()))
+                    .configure(TelemetryServer::routes),
+            )
+            .await
+        };
+    }
+
+    #[actix_web::test]
+    async fn home() {
+        let app = app!(server());
+        let res: Value =
+            test::call_and_read_body_json(&app, test::TestRequest::get().uri("/").to_request())
+                .await;
+
+        assert_eq!(res["data"]["message"], "hello, world.");
+    }
+
+    #[actix_web::test]
+    async fn transparency_lists_declared_fields() {
+        let app = app!(server());
+        test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/track")
+                .set_payload(EVENT.replace("charted-server", "ume"))
+                .to_request(),
+        )
+        .await;
+
+        let report: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get()
+                .uri("/transparency.json")
+                .to_request(),
+        )
+        .await;
+
+        // products that aren't declared are only counted, so clients can't add them to the page
+        let products = report["data"]["products"].as_array().unwrap();
+        assert_eq!(products.len(), 2);
+        assert_eq!(products[0]["product"], "charted-server");
+        assert_eq!(products[0]["fields"][0]["name"], "cluster.nodes");
+        assert_eq!(products[0]["retention_days"], 30);
+        assert_eq!(products[1]["product"], "other");
+        assert_eq!(products[1]["fields"], Value::Null);
+        assert_eq!(products[1]["events"], 1);
+
+        let page = test::call_and_read_body(
+            &app,
+            test::TestRequest::get().uri("/transparency").to_request(),
+        )
+        .await;
+        assert!(String::from_utf8_lossy(&page).contains("<code>data.cluster.nodes</code>"));
+    }
+
+    #[actix_web::test]
+    async fn dashboard_requires_logging_in() {
+        let app = app!(server());
+        test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/track")
+                .set_payload(EVENT)
+                .to_request(),
+        )
+        .await;
+
+        let page = test::call_and_read_body(
+            &app,
+            test::TestRequest::get().uri("/dashboard").to_request(),
+        )
+        .await;
+        assert!(String::from_utf8_lossy(&page).contains("/dashboard/login"));
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/dashboard/api/overview")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/dashboard/login")
+                .set_form([("token", "uwu")])
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+        assert_ne!(cookie.value(), "uwu");
+        assert_eq!(cookie.secure(), Some(true));
+
+        let overview: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get()
+                .uri("/dashboard/api/overview?days=7")
+                .cookie(cookie)
+                .to_request(),
+        )
+        .await;
+
+        assert_eq!(overview["data"]["total"], 1);
+        assert_eq!(overview["data"]["granularity"], "day");
+        assert_eq!(overview["data"]["volume"].as_array().unwrap().len(), 8);
+        assert_eq!(
+            overview["data"]["breakdowns"]["product"],
+            json!([{ "value": "charted-server", "count": 1 }])
+        );
+    }
+
+    #[actix_web::test]
+    async fn probes_report_dependencies() {
+        let app = app!(server());
+        let res =
+            test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let readiness: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get().uri("/readyz").to_request(),
+        )
+        .await;
+
+        assert_eq!(readiness["data"]["ready"], true);
+        assert_eq!(readiness["data"]["cached"], false);
+        assert_eq!(readiness["data"]["checks"]["memory"]["status"], "up");
+        assert_eq!(readiness["data"]["checks"]["tables"]["status"], "up");
+
+        let readiness: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get().uri("/readyz").to_request(),
+        )
+        .await;
+        assert_eq!(readiness["data"]["cached"], true);
+    }
+
+    #[actix_web::test]
+    async fn track_stores_events() {
+        let server = server();
+        let app = app!(server);
+
+        let req = test::TestRequest::post()
+            .uri("/track")
+            .set_payload(EVENT)
+            .to_request();
+
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let stats: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get().uri("/stats").to_request(),
+        )
+        .await;
+
+        assert_eq!(stats["data"]["events_emitted"], 1);
+        let events = server.store.events(&Default::default()).await.unwrap();
+        assert_eq!(events.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn metrics_are_exposed() {
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(server()))
+                .wrap_fn(TelemetryServer::observe)
+                .configure(TelemetryServer::routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/track")
+            .set_payload(EVENT.replace("charted-server", "metrics-test"))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let body =
+            test::call_and_read_body(&app, test::TestRequest::get().uri("/metrics").to_request())
+                .await;
+
+        // products that aren't declared in the configuration are counted as "other"
+        let metrics = String::from_utf8_lossy(&body);
+        assert!(metrics.contains(r#"telemetry_ingested_events_total{product="other"}"#));
+        assert!(!metrics.contains("metrics-test"));
+        assert!(metrics.contains(
+            r#"telemetry_http_requests_total{method="POST",route="/track",status="201"}"#
+        ));
+        assert!(metrics.contains("telemetry_storage_insert_duration_seconds_bucket"));
+    }
+
+    #[actix_web::test]
+    async fn track_refuses_events_while_shutting_down() {
+        let server = server();
+        let app = app!(server);
+        server.shutdown.begin();
+
+        let req = test::TestRequest::post()
+            .uri("/track")
+            .set_payload(EVENT)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let res =
+            test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_web::test]
+    async fn track_rejects_invalid_events() {
+        let app = app!(server());
+
+        let req = test::TestRequest::post()
+            .uri("/track")
+            .set_payload("{")
+            .to_request();
+
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post()
+            .uri("/track")
+            .set_payload(EVENT.replace("\"Noelware\"", "\"\""))
+            .to_request();
+
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let letters: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get()
+                .uri("/admin/dead-letters")
+                .insert_header((AUTHORIZATION, "Bearer owo"))
+                .to_request(),
+        )
+        .await;
+
+        let letters = letters["data"].as_array().unwrap();
+        assert_eq!(letters.len(), 2);
+        assert_eq!(letters[0]["reason"], "VALIDATION_FAILED");
+        assert_eq!(letters[0]["product"], "charted-server");
+        assert_eq!(letters[1]["reason"], "INVALID_JSON");
+
+        let id = letters[1]["id"].as_u64().unwrap();
+        let letter: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/admin/dead-letters/{}", id))
+                .insert_header((AUTHORIZATION, "Bearer owo"))
+                .to_request(),
+        )
+        .await;
+
+        assert_eq!(letter["data"]["body"], "{");
+    }
+
+    #[actix_web::test]
+    async fn events_are_filtered_and_paginated() {
+        let app = app!(server());
+        for nodes in [1, 2, 3] {
+            let req = test::TestRequest::post()
+                .uri("/track")
+                .set_payload(EVENT.replace(
+                    r#"{"hello":"world"}"#,
+                    &format!(r#"{{"cluster":{{"nodes":{}}}}}"#, nodes),
+                ))
+                .to_request();
+
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::CREATED
+            );
+        }
+
+        let page: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get()
+                .uri("/v1/events?product=charted-server&limit=2")
+                .insert_header((AUTHORIZATION, "Bearer owo"))
+                .to_request(),
+        )
+        .await;
+
+        let events = page["data"]["events"].as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["data"]["cluster"]["nodes"], 3);
+
+        let cursor = page["data"]["next_cursor"].as_str().unwrap();
+        let page: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/v1/events?limit=2&cursor={}", cursor))
+                .insert_header((AUTHORIZATION, "Bearer owo"))
+                .to_request(),
+        )
+        .await;
+
+        assert_eq!(page["data"]["events"].as_array().unwrap().len(), 1);
+        assert_eq!(page["data"]["next_cursor"], Value::Null);
+
+        let page: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get()
+                .uri("/v1/events?data.cluster.nodes=2")
+                .insert_header((AUTHORIZATION, "Bearer owo"))
+                .to_request(),
+        )
+        .await;
+
+        assert_eq!(page["data"]["events"].as_array().unwrap().len(), 1);
+        assert_eq!(page["data"]["events"][0]["data"]["cluster"]["nodes"], 2);
+    }
+
+    #[actix_web::test]
+    async fn aggregates_events_by_dimensions() {
+        let app = app!(server());
+        for (os, nodes) in [("linux", 1), ("linux", 3), ("darwin", 3)] {
+            let req = test::TestRequest::post()
+                .uri("/track")
+                .set_payload(
+                    EVENT
+                        .replace(r#""linux""#, &format!(r#""{}""#, os))
+                        .replace(
+                            r#"{"hello":"world"}"#,
+                            &format!(r#"{{"cluster":{{"nodes":{}}}}}"#, nodes),
+                        ),
+                )
+                .to_request();
+
+            test::call_service(&app, req).await;
+        }
+
+        let res: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get()
+                .uri("/v1/aggregate?group_by=os,data.cluster.nodes&granularity=week&timezone=America/Toronto")
+                .insert_header((AUTHORIZATION, "Bearer owo"))
+                .to_request(),
+        )
+        .await;
+
+        let series = res["data"]["series"].as_array().unwrap();
+        assert_eq!(res["data"]["timezone"], "America/Toronto");
+        assert_eq!(series.len(), 3);
+        assert_eq!(series[0]["group"]["os"], "darwin");
+        assert_eq!(series[0]["group"]["data.cluster.nodes"], "3");
+        assert_eq!(series[0]["total"], 1);
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/v1/aggregate?timezone=Nowhere")
+                .insert_header((AUTHORIZATION, "Bearer owo"))
+                .to_request(),
+        )
+        .await;
+
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn reports_version_adoption() {
+        let app = app!(server());
+        for (installation, version) in [("a", "0.1.0"), ("b", "0.1.0"), ("a", "0.2.0")] {
+            let req = test::TestRequest::post()
+                .uri("/track")
+                .set_payload(
+                    EVENT
+                        .replace(r#""0.1.0""#, &format!(r#""{}""#, version))
+                        .replace(
+                            r#""data""#,
+                            &format!(r#""installation_id":"{}","data""#, installation),
+                        ),
+                )
+                .to_request();
+
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::CREATED
+            );
+        }
+
+        let res: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get()
+                .uri("/v1/versions?product=charted-server")
+                .insert_header((AUTHORIZATION, "Bearer owo"))
+                .to_request(),
+        )
+        .await;
+
+        let bucket = &res["data"]["buckets"][0];
+        assert_eq!(bucket["installations"], 2);
+        assert_eq!(bucket["versions"][0]["version"], "0.1.0");
+        assert_eq!(bucket["versions"][0]["share"], 0.5);
+        assert_eq!(res["data"]["transitions"][0]["from"], "0.1.0");
+        assert_eq!(res["data"]["transitions"][0]["to"], "0.2.0");
+        assert_eq!(res["data"]["transitions"][0]["installations"], 1);
+    }
+
+    #[actix_web::test]
+    async fn retention_reports_products() {
+        let server = server();
+        let app = app!(server);
+
+        let event = |id, product: &str, days| {
+            Event::test(id)
+                .with_product(product)
+                .fired_at(Utc::now() - Duration::days(days))
+        };
+
+        server
+            .store
+            .insert_events(vec![
+                event(1, "charted-server", 1),
+                event(2, "charted-server", 60),
+                event(3, "ume", 1),
+            ])
+            .await
+            .unwrap();
+
+        let report: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get()
+                .uri("/admin/retention")
+                .insert_header((AUTHORIZATION, "Bearer owo"))
+                .to_request(),
+        )
+        .await;
+
+        assert_eq!(report["data"][0]["product"], "charted-server");
+        assert_eq!(report["data"][0]["retention_days"], 30);
+        assert_eq!(report["data"][0]["events"], 2);
+        assert_eq!(report["data"][0]["overdue"], true);
+        assert_eq!(report["data"][1]["retention_days"], 7);
+
+        server
+            .store
+            .apply_retention(server.config.get().retention.as_ref().unwrap())
+            .await
+            .unwrap();
+
+        let report: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get()
+                .uri("/admin/retention")
+                .insert_header((AUTHORIZATION, "Bearer owo"))
+                .to_request(),
+        )
+        .await;
+
+        assert_eq!(report["data"][0]["events"], 1);
+        assert_eq!(report["data"][0]["overdue"], false);
+    }
+
+    #[actix_web::test]
+    async fn admin_routes_require_token() {
+        let app = app!(server());
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/admin/dead-letters")
+                .to_request(),
+        )
+        .await;
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/admin/dead-letters")
+                .insert_header((AUTHORIZATION, "Bearer uwu"))
+                .to_request(),
+        )
+        .await;
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -24,21 +24,30 @@ use ansi_term::Colour::RGB;
 use chrono::Local;
 use fern::Dispatch;
 use log::{LevelFilter, Log};
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{Sampler, TracerProvider},
+    Resource,
+};
 use regex::Regex;
 use sentry::types::Dsn;
 use sentry_log::{NoopLogger, SentryLogger};
 use serde_json::json;
+use tracing_subscriber::{layer::SubscriberExt, Registry};
 
 use crate::{config::Config, constants};
 
 #[allow(dead_code)]
 const ANSI_TERM_REGEX: &str = r#"\u001b\[.*?m"#;
 
-pub fn setup_sentry(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+pub fn setup_sentry(config: &Config) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     if let Some(dsn) = &config.sentry_dsn {
         debug!("Sentry DSN was provided! Now enabling...");
         let _ = sentry::init(sentry::ClientOptions {
-            dsn: Some(Dsn::from_str(dsn.as_str())?),
+            dsn: Some(Dsn::from_str(dsn.expose())?),
             ..Default::default()
         });
     }
@@ -46,23 +55,65 @@ pub fn setup_sentry(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-pub fn setup_logging(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
-    let default_level = &"info".to_string();
-    let sentry_enabled = config.sentry_dsn.is_some();
+/// Sets up the `tracing` subscriber, which exports spans to an OpenTelemetry collector if
+/// `config.tracing.otlp_endpoint` is set. Incoming requests are always able to continue the
+/// trace from their W3C `traceparent` header.
+pub fn setup_tracing(config: &Config) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
 
-    let level = if let Some(log) = &config.logging {
-        let l = log.level.as_ref();
-        l.unwrap_or(default_level)
-    } else {
-        default_level
-    };
+    let otlp = match config
+        .tracing
+        .as_ref()
+        .and_then(|t| t.otlp_endpoint.as_ref())
+    {
+        Some(endpoint) => {
+            let tracing = config.tracing.as_ref().unwrap();
+            debug!("exporting spans to OpenTelemetry collector at {}", endpoint);
 
-    let is_json = match &config.logging {
-        Some(cfg) => cfg.json.unwrap_or(false),
-        None => false,
+            let exporter = SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .build()?;
+
+            let provider = TracerProvider::builder()
+                .with_batch_exporter(exporter, runtime::Tokio)
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    tracing.sample_ratio.unwrap_or(1.0),
+                ))))
+                .with_resource(Resource::new(vec![
+                    KeyValue::new(
+                        "service.name",
+                        tracing
+                            .service_name
+                            .clone()
+                            .unwrap_or_else(|| "telemetry-server".into()),
+                    ),
+                    KeyValue::new("service.version", constants::VERSION),
+                ]))
+                .build();
+
+            global::set_tracer_provider(provider.clone());
+            Some(tracing_opentelemetry::layer().with_tracer(provider.tracer("telemetry-server")))
+        }
+
+        None => None,
     };
 
-    let log_level = match level.as_str() {
+    let sentry = config.sentry_dsn.as_ref().map(|_| sentry_tracing::layer());
+    tracing::subscriber::set_global_default(Registry::default().with(otlp).with(sentry))?;
+
+    Ok(())
+}
+
+/// Returns the level that `config.logging.level` is set to, which defaults to `info`.
+pub fn log_level(config: &Config) -> LevelFilter {
+    let level = config
+        .logging
+        .as_ref()
+        .and_then(|log| log.level.as_deref())
+        .unwrap_or("info");
+
+    match level {
         "off" => log::LevelFilter::Off,
         "error" => log::LevelFilter::Error,
         "warn" => log::LevelFilter::Warn,
@@ -70,6 +121,14 @@ pub fn setup_logging(config: &Config) -> Result<(), Box<dyn std::error::Error>>
         "debug" => log::LevelFilter::Debug,
         "trace" => log::LevelFilter::Trace,
         _ => log::LevelFilter::Info,
+    }
+}
+
+pub fn setup_logging(config: &Config) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let sentry_enabled = config.sentry_dsn.is_some();
+    let is_json = match &config.logging {
+        Some(cfg) => cfg.json.unwrap_or(false),
+        None => false,
     };
 
     let dispatch = Dispatch::new()
@@ -154,7 +213,9 @@ pub fn setup_logging(config: &Config) -> Result<(), Box<dyn std::error::Error>>
                 out.finish(format_args!("{time} {level} {b1}{target} {c1}{thread_name}{c2} {p1}{pid_colour}{p2}{b2} :: {}", message));
             }
         })
-        .level(log_level)
+        // the level is enforced by `log::set_max_level` rather than the dispatch, so it can be
+        // changed when the configuration is reloaded.
+        .level(LevelFilter::Trace)
         .chain(std::io::stdout())
         .chain(if sentry_enabled {
             Box::new(SentryLogger::default()) as Box<dyn Log>
@@ -205,7 +266,7 @@ pub fn setup_logging(config: &Config) -> Result<(), Box<dyn std::error::Error>>
                             out.finish(format_args!("{}", data));
                         })
                         .chain(Box::new(stream) as Box<dyn Write + Send>)
-                        .level(log_level)
+                        .level(LevelFilter::Trace)
                 }
                 None => Dispatch::new().level(LevelFilter::Off) // this will be dropped since it has no children and the log filter is Off.
             },
@@ -213,5 +274,7 @@ pub fn setup_logging(config: &Config) -> Result<(), Box<dyn std::error::Error>>
         });
 
     dispatch.apply()?;
+    log::set_max_level(log_level(config));
+
     Ok(())
 }
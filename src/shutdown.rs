@@ -0,0 +1,136 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+#[derive(Debug, Default)]
+struct State {
+    shutting_down: AtomicBool,
+    in_flight: AtomicUsize,
+    aborted: AtomicUsize,
+}
+
+/// Coordinates shutting down the server, by refusing new events once it began and keeping
+/// track of the ones that are still being ingested.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    state: Arc<State>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    pub fn begin(&self) {
+        self.state.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// How many events are being ingested right now.
+    pub fn in_flight(&self) -> usize {
+        self.state.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// How many events were dropped before they could be stored.
+    pub fn aborted(&self) -> usize {
+        self.state.aborted.load(Ordering::SeqCst)
+    }
+
+    /// Starts tracking an event that is being ingested, or returns `None` if the server
+    /// is shutting down and shouldn't accept it.
+    pub fn track(&self, description: String) -> Option<InFlight> {
+        if self.is_shutting_down() {
+            return None;
+        }
+
+        self.state.in_flight.fetch_add(1, Ordering::SeqCst);
+        Some(InFlight {
+            state: self.state.clone(),
+            description,
+            finished: false,
+        })
+    }
+}
+
+/// Represents an event that is being ingested. If it is dropped before [`InFlight::finish`]
+/// is called, the request was aborted (i.e, the shutdown deadline passed) and it is logged.
+#[derive(Debug)]
+pub struct InFlight {
+    state: Arc<State>,
+    description: String,
+    finished: bool,
+}
+
+impl InFlight {
+    pub fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.state.in_flight.fetch_sub(1, Ordering::SeqCst);
+        if !self.finished {
+            self.state.aborted.fetch_add(1, Ordering::SeqCst);
+            warn!("aborted {} before it was stored", self.description);
+        }
+    }
+}
+
+/// Waits until the process is asked to stop, and returns the name of the signal.
+#[cfg(unix)]
+pub async fn signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "Ctrl+C"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shutdown;
+
+    #[test]
+    fn refuses_events_and_counts_aborted_ones() {
+        let shutdown = Shutdown::new();
+        let stored = shutdown.track("event 1".into()).unwrap();
+        let aborted = shutdown.track("event 2".into()).unwrap();
+        assert_eq!(shutdown.in_flight(), 2);
+
+        shutdown.begin();
+        assert!(shutdown.track("event 3".into()).is_none());
+
+        stored.finish();
+        drop(aborted);
+        assert_eq!(shutdown.in_flight(), 0);
+        assert_eq!(shutdown.aborted(), 1);
+    }
+}
@@ -38,7 +38,11 @@ impl Snowflake {
 
     pub fn generate(&mut self) -> i64 {
         let now = SystemTime::now();
-        let time: DateTime<Utc> = now.into();
+        self.generate_at(now.into())
+    }
+
+    /// Generates a snowflake for the given point in time.
+    pub fn generate_at(&mut self, time: DateTime<Utc>) -> i64 {
         let timestamp = time.timestamp_millis();
 
         let mut increment = self.increment.try_lock().unwrap();
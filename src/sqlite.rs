@@ -0,0 +1,698 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt::{self, Debug, Formatter},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use async_trait::async_trait;
+use chrono::{Duration, TimeZone, Utc};
+use rusqlite::{params, types::Value, Connection, Row, ToSql};
+
+use crate::{
+    analytics::{CountBucket, Counts, CountsQuery, EventQuery},
+    config::{RetentionConfig, SQLiteConfig},
+    dead_letters::{BodyEncoding, DeadLetter, DeadLetterQuery, RejectionReason},
+    metrics::METRICS,
+    migrations::{self, AppliedMigration, Migration},
+    storage::{Event, EventStore, ProductStats, Result},
+};
+
+const SCHEMA_MIGRATIONS: &str = r#"
+CREATE TABLE IF NOT EXISTS schema_migrations(
+    Version INTEGER NOT NULL PRIMARY KEY,
+    Name TEXT NOT NULL,
+    AppliedAt INTEGER NOT NULL
+);
+"#;
+
+/// Embedded SQLite backend, for small self-hosted deployments that don't want
+/// to run ClickHouse. Every call is run on tokio's blocking thread pool since
+/// rusqlite is synchronous.
+#[derive(Clone)]
+pub struct SQLite {
+    path: String,
+    conn: Arc<Mutex<Connection>>,
+    calls: Arc<AtomicUsize>,
+}
+
+impl Debug for SQLite {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SQLite").field("path", &self.path).finish()
+    }
+}
+
+impl SQLite {
+    pub fn open(config: Option<&SQLiteConfig>) -> Result<SQLite> {
+        let path = config
+            .and_then(|c| c.path.clone())
+            .unwrap_or_else(|| "./telemetry.db".into());
+
+        let conn = if path == ":memory:" {
+            Connection::open_in_memory()?
+        } else {
+            Connection::open(&path)?
+        };
+
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+
+        Ok(SQLite {
+            path,
+            conn: Arc::new(Mutex::new(conn)),
+            calls: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    async fn run<F, T>(&self, func: F) -> Result<T>
+    where
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.conn.clone();
+        self.calls.fetch_add(1, Ordering::SeqCst);
+
+        let result = tokio::task::spawn_blocking(move || {
+            // there's only one connection, so waiting on it is our equivalent of a pool.
+            let started = Instant::now();
+            let mut conn = conn.lock().unwrap();
+            METRICS.observe_pool_wait("sqlite", true, started.elapsed());
+
+            func(&mut conn)
+        })
+        .await?;
+
+        Ok(result?)
+    }
+}
+
+#[async_trait]
+impl EventStore for SQLite {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.run(|conn| conn.query_row("SELECT 1", [], |_| Ok(())))
+            .await
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        migrations::SQLITE
+    }
+
+    async fn close(&self) -> Result<()> {
+        // moves everything from the write-ahead log into the database file, so the
+        // database is complete on its own when the server isn't running.
+        self.run(|conn| conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)"))
+            .await
+    }
+
+    async fn missing_tables(&self) -> Result<Vec<String>> {
+        let tables = self
+            .run(|conn| {
+                let mut stmt =
+                    conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table'")?;
+                let tables = stmt
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+
+                Ok(tables)
+            })
+            .await?;
+
+        Ok(["events", "dead_letters", "schema_migrations"]
+            .into_iter()
+            .filter(|table| !tables.iter().any(|t| t == table))
+            .map(String::from)
+            .collect())
+    }
+
+    async fn applied_migrations(&self) -> Result<Vec<AppliedMigration>> {
+        self.run(|conn| {
+            conn.execute_batch(SCHEMA_MIGRATIONS)?;
+
+            let mut stmt = conn.prepare(
+                "SELECT Version, Name, AppliedAt FROM schema_migrations ORDER BY Version",
+            )?;
+
+            let rows = stmt.query_map([], |row| {
+                Ok(AppliedMigration {
+                    version: row.get("Version")?,
+                    name: row.get("Name")?,
+                    applied_at: Utc.timestamp_millis_opt(row.get("AppliedAt")?).unwrap(),
+                })
+            })?;
+
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        let migration = *migration;
+        self.run(move |conn| {
+            // SQLite can run DDL statements in a transaction, so a migration
+            // either applies fully or not at all.
+            let tx = conn.transaction()?;
+            tx.execute_batch(SCHEMA_MIGRATIONS)?;
+            tx.execute_batch(migration.sql)?;
+            tx.execute(
+                "INSERT INTO schema_migrations(Version, Name, AppliedAt) VALUES (?1, ?2, ?3)",
+                params![
+                    migration.version,
+                    migration.name,
+                    Utc::now().timestamp_millis()
+                ],
+            )?;
+
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn insert_events(&self, events: Vec<Event>) -> Result<()> {
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(
+                    "INSERT INTO events(ID, Product, Vendor, InstallationID, Distribution, Version, Arch, OS, FiredAt, Data) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                )?;
+
+                for event in events {
+                    stmt.execute(params![
+                        event.id as i64,
+                        event.product,
+                        event.vendor,
+                        event.installation_id,
+                        event.distribution,
+                        event.version,
+                        event.arch,
+                        event.os,
+                        event.fired_at.timestamp_millis(),
+                        event.data.to_string()
+                    ])?;
+                }
+            }
+
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn count_events(&self) -> Result<u64> {
+        self.run(|conn| {
+            conn.query_row("SELECT COUNT(*) FROM events", [], |row| {
+                row.get::<_, i64>(0)
+            })
+        })
+        .await
+        .map(|count| count as u64)
+    }
+
+    async fn events(&self, query: &EventQuery) -> Result<Vec<Event>> {
+        let mut conditions = vec![];
+        let mut values: Vec<Value> = vec![];
+        for (column, value) in query.filters() {
+            conditions.push(format!("{} = ?", column));
+            values.push(value.to_string().into());
+        }
+
+        if let Some(from) = query.from {
+            conditions.push("FiredAt >= ?".into());
+            values.push(from.timestamp_millis().into());
+        }
+
+        if let Some(to) = query.to {
+            conditions.push("FiredAt < ?".into());
+            values.push(to.timestamp_millis().into());
+        }
+
+        if let Some(before) = query.before {
+            conditions.push("ID < ?".into());
+            values.push((before as i64).into());
+        }
+
+        for filter in query.data.iter() {
+            let path = json_path(&filter.path);
+            conditions.push(
+                "CASE json_type(Data, ?) WHEN 'text' THEN Data ->> ? ELSE Data -> ? END = ?".into(),
+            );
+
+            values.extend([
+                path.clone().into(),
+                path.clone().into(),
+                path.into(),
+                filter.value.clone().into(),
+            ]);
+        }
+
+        values.push((query.limit() as i64).into());
+        let sql = format!(
+            "SELECT ID, Product, Vendor, InstallationID, Distribution, Version, Arch, OS, FiredAt, Data FROM events{} ORDER BY ID DESC LIMIT ?",
+            if conditions.is_empty() {
+                String::new()
+            } else {
+                format!(" WHERE {}", conditions.join(" AND "))
+            }
+        );
+
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(values.iter()), row_to_event)?;
+
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn product_stats(&self) -> Result<Vec<ProductStats>> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT Product, COUNT(*), MIN(FiredAt) FROM events GROUP BY Product ORDER BY Product",
+            )?;
+
+            let rows = stmt.query_map([], |row| {
+                Ok(ProductStats {
+                    product: row.get(0)?,
+                    events: row.get::<_, i64>(1)? as u64,
+                    oldest_event: row
+                        .get::<_, Option<i64>>(2)?
+                        .and_then(|millis| Utc.timestamp_millis_opt(millis).single()),
+                })
+            })?;
+
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn apply_retention(&self, retention: &RetentionConfig) -> Result<()> {
+        let retention = retention.clone();
+        let now = Utc::now();
+        let cutoff = move |days: u32| (now - Duration::days(days as i64)).timestamp_millis();
+
+        let deleted = self
+            .run(move |conn| {
+                let tx = conn.transaction()?;
+                let mut deleted = 0;
+                let products = retention.products.clone().unwrap_or_default();
+
+                for (product, days) in products.iter() {
+                    deleted += tx.execute(
+                        "DELETE FROM events WHERE Product = ?1 AND FiredAt < ?2",
+                        params![product, cutoff(*days)],
+                    )?;
+                }
+
+                if let Some(days) = retention.default_days {
+                    let mut values: Vec<Box<dyn ToSql>> = vec![Box::new(cutoff(days))];
+                    let placeholders = products
+                        .keys()
+                        .map(|product| {
+                            values.push(Box::new(product.clone()));
+                            "?"
+                        })
+                        .collect::<Vec<_>>();
+
+                    deleted += tx.execute(
+                        &format!(
+                            "DELETE FROM events WHERE FiredAt < ? AND Product NOT IN ({})",
+                            placeholders.join(", ")
+                        ),
+                        rusqlite::params_from_iter(values.iter().map(|v| v.as_ref())),
+                    )?;
+                }
+
+                tx.commit()?;
+                Ok(deleted)
+            })
+            .await?;
+
+        if deleted > 0 {
+            info!("deleted {} events that expired", deleted);
+        }
+
+        Ok(())
+    }
+
+    async fn apply_dead_letter_retention(&self, days: u32) -> Result<()> {
+        let cutoff = (Utc::now() - Duration::days(days as i64)).timestamp_millis();
+        let deleted = self
+            .run(move |conn| {
+                conn.execute(
+                    "DELETE FROM dead_letters WHERE ReceivedAt < ?1",
+                    params![cutoff],
+                )
+            })
+            .await?;
+
+        if deleted > 0 {
+            info!("deleted {} dead letters that expired", deleted);
+        }
+
+        Ok(())
+    }
+
+    async fn event_counts(&self, query: &CountsQuery) -> Result<Counts> {
+        let granularity = query.granularity();
+        let millis = granularity.seconds() * 1000;
+        let offset = granularity.epoch_offset() * 1000;
+
+        let mut conditions = vec![];
+        let mut values: Vec<Value> = vec![];
+        for (column, value) in query.filters() {
+            conditions.push(format!("{} = ?", column));
+            values.push(value.to_string().into());
+        }
+
+        if let Some(from) = query.from {
+            conditions.push("FiredAt >= ?".into());
+            values.push(from.timestamp_millis().into());
+        }
+
+        if let Some(to) = query.to {
+            conditions.push("FiredAt < ?".into());
+            values.push(to.timestamp_millis().into());
+        }
+
+        let sql = format!(
+            "SELECT ((FiredAt + {offset}) / {millis}) * {millis} - {offset} AS Bucket, COUNT(*) FROM events{} GROUP BY Bucket ORDER BY Bucket",
+            if conditions.is_empty() {
+                String::new()
+            } else {
+                format!(" WHERE {}", conditions.join(" AND "))
+            }
+        );
+
+        let buckets = self
+            .run(move |conn| {
+                let mut stmt = conn.prepare(&sql)?;
+                let rows = stmt.query_map(rusqlite::params_from_iter(values.iter()), |row| {
+                    Ok(CountBucket {
+                        time: Utc.timestamp_millis_opt(row.get(0)?).unwrap(),
+                        count: row.get::<_, i64>(1)? as u64,
+                    })
+                })?;
+
+                rows.collect()
+            })
+            .await?;
+
+        Ok(Counts {
+            granularity,
+            source: "events",
+            buckets,
+        })
+    }
+
+    async fn insert_dead_letter(&self, letter: &DeadLetter) -> Result<()> {
+        let letter = letter.clone();
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO dead_letters(ID, ReceivedAt, Reason, Product, Message, Body, Encoding) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    letter.id as i64,
+                    letter.received_at.timestamp_millis(),
+                    letter.reason.code(),
+                    letter.product,
+                    letter.message,
+                    letter.body,
+                    letter.encoding.code()
+                ],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn dead_letters(&self, query: &DeadLetterQuery) -> Result<Vec<DeadLetter>> {
+        let reason = query.reason.clone();
+        let product = query.product.clone();
+        let limit = query.limit() as i64;
+
+        self.run(move |conn| {
+            let mut conditions = vec![];
+            let mut values: Vec<Box<dyn ToSql>> = vec![];
+            if let Some(reason) = reason {
+                conditions.push("Reason = ?");
+                values.push(Box::new(reason));
+            }
+
+            if let Some(product) = product {
+                conditions.push("Product = ?");
+                values.push(Box::new(product));
+            }
+
+            values.push(Box::new(limit));
+            let sql = format!(
+                "SELECT ID, ReceivedAt, Reason, Product, Message, Body, Encoding FROM dead_letters{} ORDER BY ID DESC LIMIT ?",
+                if conditions.is_empty() {
+                    String::new()
+                } else {
+                    format!(" WHERE {}", conditions.join(" AND "))
+                }
+            );
+
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(
+                rusqlite::params_from_iter(values.iter().map(|v| v.as_ref())),
+                row_to_letter,
+            )?;
+
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn dead_letter(&self, id: u64) -> Result<Option<DeadLetter>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT ID, ReceivedAt, Reason, Product, Message, Body, Encoding FROM dead_letters WHERE ID = ?1",
+            )?;
+
+            let mut rows = stmt.query_map(params![id as i64], row_to_letter)?;
+            rows.next().transpose()
+        })
+        .await
+    }
+}
+
+fn row_to_event(row: &Row<'_>) -> rusqlite::Result<Event> {
+    let data: String = row.get("Data")?;
+    Ok(Event {
+        id: row.get::<_, i64>("ID")? as u64,
+        product: row.get("Product")?,
+        vendor: row.get("Vendor")?,
+        installation_id: row.get("InstallationID")?,
+        distribution: row.get("Distribution")?,
+        version: row.get("Version")?,
+        arch: row.get("Arch")?,
+        os: row.get("OS")?,
+        data: serde_json::from_str(&data).unwrap_or(serde_json::Value::String(data)),
+        fired_at: Utc.timestamp_millis_opt(row.get("FiredAt")?).unwrap(),
+    })
+}
+
+/// Returns the SQLite JSON path for the given keys, i.e. `$."cluster"."nodes"`.
+fn json_path(keys: &[String]) -> String {
+    keys.iter().fold(String::from("$"), |path, key| {
+        format!("{}.\"{}\"", path, key)
+    })
+}
+
+fn row_to_letter(row: &Row<'_>) -> rusqlite::Result<DeadLetter> {
+    let reason: String = row.get("Reason")?;
+    let encoding: String = row.get("Encoding")?;
+    let received_at: i64 = row.get("ReceivedAt")?;
+
+    Ok(DeadLetter {
+        id: row.get::<_, i64>("ID")? as u64,
+        reason: RejectionReason::from_code(reason.as_str()).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(
+                2,
+                rusqlite::types::Type::Text,
+                format!("unknown rejection reason {}", reason).into(),
+            )
+        })?,
+        product: row.get("Product")?,
+        message: row.get("Message")?,
+        body: row.get("Body")?,
+        encoding: BodyEncoding::from_code(encoding.as_str()).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(
+                6,
+                rusqlite::types::Type::Text,
+                format!("unknown body encoding {}", encoding).into(),
+            )
+        })?,
+        received_at: Utc.timestamp_millis_opt(received_at).unwrap(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::SQLite;
+    use crate::{
+        analytics::{CountsQuery, DataFilter, EventQuery, Granularity},
+        config::SQLiteConfig,
+        dead_letters::{DeadLetter, DeadLetterQuery, RejectionReason},
+        migrations,
+        storage::{Event, EventStore},
+    };
+
+    fn open() -> SQLite {
+        SQLite::open(Some(&SQLiteConfig {
+            path: Some(":memory:".into()),
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn stores_and_counts_events() {
+        let sqlite = open();
+        assert_eq!(sqlite.missing_tables().await.unwrap().len(), 3);
+
+        migrations::up(&sqlite, false).await.unwrap();
+        sqlite.ping().await.unwrap();
+        assert!(sqlite.missing_tables().await.unwrap().is_empty());
+
+        sqlite
+            .insert_events(vec![
+                Event::test(1).with_data(json!({ "hello": "world", "nodes": 3 }))
+            ])
+            .await
+            .unwrap();
+
+        assert_eq!(sqlite.count_events().await.unwrap(), 1);
+        for (path, value, expected) in [("hello", "world", 1), ("nodes", "3", 1), ("nodes", "4", 0)]
+        {
+            let events = sqlite
+                .events(&EventQuery {
+                    data: vec![DataFilter {
+                        path: vec![path.into()],
+                        value: value.into(),
+                    }],
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+
+            assert_eq!(events.len(), expected);
+        }
+
+        let counts = sqlite
+            .event_counts(&CountsQuery {
+                product: Some("charted-server".into()),
+                granularity: Some(Granularity::Day),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(counts.buckets.len(), 1);
+        assert_eq!(counts.buckets[0].count, 1);
+        assert_eq!(
+            counts.buckets[0].time,
+            Granularity::Day.truncate(Utc::now())
+        );
+    }
+
+    #[tokio::test]
+    async fn backfills_envelope_fields() {
+        let sqlite = open();
+        for migration in &sqlite.migrations()[..2] {
+            sqlite.apply_migration(migration).await.unwrap();
+        }
+
+        sqlite
+            .run(|conn| {
+                conn.execute(
+                    "INSERT INTO events(Data, ID, Product, Vendor) VALUES (?1, 1, 'charted-server', 'Noelware')",
+                    [r#"{"distribution":"docker","version":"0.1.0","arch":"x86_64","os":"linux","data":{"hello":"world"},"fired_at":"2023-04-01T12:30:00.250+00:00"}"#],
+                )
+            })
+            .await
+            .unwrap();
+
+        migrations::up(&sqlite, false).await.unwrap();
+        let (version, fired_at, data) = sqlite
+            .run(|conn| {
+                conn.query_row(
+                    "SELECT Version, FiredAt, Data FROM events WHERE ID = 1",
+                    [],
+                    |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get::<_, i64>(1)?,
+                            row.get::<_, String>(2)?,
+                        ))
+                    },
+                )
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(version, "0.1.0");
+        assert_eq!(fired_at, 1680352200250);
+        assert_eq!(data, r#"{"hello":"world"}"#);
+    }
+
+    #[tokio::test]
+    async fn stores_and_lists_dead_letters() {
+        let sqlite = open();
+        migrations::up(&sqlite, false).await.unwrap();
+
+        for (id, reason) in [
+            (1, RejectionReason::InvalidJson),
+            (2, RejectionReason::ValidationFailed),
+        ] {
+            sqlite
+                .insert_dead_letter(&DeadLetter::new(
+                    id,
+                    reason,
+                    br#"{"product":"charted-server"}"#,
+                    "nope".into(),
+                    Utc::now(),
+                ))
+                .await
+                .unwrap();
+        }
+
+        let letters = sqlite
+            .dead_letters(&DeadLetterQuery {
+                reason: Some("INVALID_JSON".into()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].product, Some("charted-server".into()));
+        assert!(sqlite.dead_letter(2).await.unwrap().is_some());
+        assert!(sqlite.dead_letter(3).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,58 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex;
+
+use crate::storage::{EventStore, Result};
+
+/// How long the number of stored events is served from the cache, so `/stats` doesn't
+/// count every event on each request.
+pub const CACHE_FOR: Duration = Duration::from_secs(60);
+
+/// Caches how many events are stored for [`CACHE_FOR`].
+#[derive(Debug, Clone)]
+pub struct EventCount {
+    store: Arc<dyn EventStore>,
+    last: Arc<Mutex<Option<(Instant, u64)>>>,
+}
+
+impl EventCount {
+    pub fn new(store: Arc<dyn EventStore>) -> EventCount {
+        EventCount {
+            store,
+            last: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Returns how many events are stored, counting them again if the cached count expired.
+    /// Concurrent callers wait on the same count rather than running their own.
+    pub async fn get(&self) -> Result<u64> {
+        let mut last = self.last.lock().await;
+        if let Some((at, count)) = last.as_ref() {
+            if at.elapsed() < CACHE_FOR {
+                return Ok(*count);
+            }
+        }
+
+        let count = self.store.count_events().await?;
+        *last = Some((Instant::now(), count));
+        Ok(count)
+    }
+}
@@ -0,0 +1,245 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt::Debug, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    adoption::{Adoption, AdoptionQuery},
+    analytics::{AggregateQuery, Aggregation, Counts, CountsQuery, EventQuery},
+    clickhouse::ClickHouse,
+    cohorts::{CohortMatrix, CohortQuery},
+    config::{Config, RetentionConfig, StorageBackend},
+    dead_letters::{DeadLetter, DeadLetterQuery},
+    funnels::{Funnel, FunnelQuery},
+    memory::Memory,
+    migrations::{AppliedMigration, Migration},
+    percentiles::{PercentileQuery, Percentiles},
+    sqlite::SQLite,
+};
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Represents a telemetry event that was accepted by the server, with everything
+/// the server enriched it with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub id: u64,
+    pub product: String,
+    pub vendor: String,
+    pub installation_id: Option<String>,
+    pub distribution: String,
+    pub version: String,
+    pub arch: String,
+    pub os: String,
+    pub data: Value,
+    pub fired_at: DateTime<Utc>,
+}
+
+#[cfg(test)]
+impl Event {
+    /// Returns an event of `charted-server` v0.1.0 that was fired just now, without an
+    /// installation or data, for tests to change what they need with the `with_*` methods.
+    pub fn test(id: u64) -> Event {
+        Event {
+            id,
+            product: "charted-server".into(),
+            vendor: "Noelware".into(),
+            installation_id: None,
+            distribution: "docker".into(),
+            version: "0.1.0".into(),
+            arch: "x86_64".into(),
+            os: "linux".into(),
+            data: Value::Object(Default::default()),
+            fired_at: Utc::now(),
+        }
+    }
+
+    pub fn with_product(mut self, product: &str) -> Event {
+        self.product = product.into();
+        self
+    }
+
+    pub fn with_installation(mut self, installation: &str) -> Event {
+        self.installation_id = Some(installation.into());
+        self
+    }
+
+    pub fn with_version(mut self, version: &str) -> Event {
+        self.version = version.into();
+        self
+    }
+
+    pub fn with_data(mut self, data: Value) -> Event {
+        self.data = data;
+        self
+    }
+
+    pub fn fired_at(mut self, fired_at: DateTime<Utc>) -> Event {
+        self.fired_at = fired_at;
+        self
+    }
+}
+
+/// How many events a product has stored, and when the oldest one was fired at.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProductStats {
+    pub product: String,
+    pub events: u64,
+    pub oldest_event: Option<DateTime<Utc>>,
+}
+
+/// Represents a backend that telemetry events (and the ones we rejected) are stored in.
+#[async_trait]
+pub trait EventStore: Debug + Send + Sync {
+    /// Name of this backend, used in logs.
+    fn name(&self) -> &'static str;
+
+    /// How many calls were made to the backend since the server started.
+    fn calls(&self) -> usize;
+
+    /// Checks if the backend is reachable.
+    async fn ping(&self) -> Result<()>;
+
+    /// Schema migrations for this backend, in the order they should be applied.
+    fn migrations(&self) -> &'static [Migration];
+
+    /// Returns the migrations that were recorded in the `schema_migrations` table,
+    /// creating the table if it doesn't exist.
+    async fn applied_migrations(&self) -> Result<Vec<AppliedMigration>>;
+
+    /// Runs the given migration and records it in the `schema_migrations` table.
+    async fn apply_migration(&self, migration: &Migration) -> Result<()>;
+
+    /// Stores the given events.
+    async fn insert_events(&self, events: Vec<Event>) -> Result<()>;
+
+    /// Returns how many events were stored.
+    async fn count_events(&self) -> Result<u64>;
+
+    /// Lists the events that match the query, newest first.
+    async fn events(&self, query: &EventQuery) -> Result<Vec<Event>>;
+
+    /// Returns every event that matches the query by going through all of its pages, which
+    /// is used to compute analytics on the backends that can't do it themselves.
+    async fn scan(&self, query: &EventQuery) -> Result<Vec<Event>> {
+        let mut query = EventQuery {
+            limit: Some(500),
+            ..query.clone()
+        };
+
+        let mut events = vec![];
+        loop {
+            let page = self.events(&query).await?;
+            let done = (page.len() as u64) < query.limit();
+
+            query.before = page.last().map(|event| event.id);
+            events.extend(page);
+
+            if done {
+                return Ok(events);
+            }
+        }
+    }
+
+    /// Counts events in each bucket for every group of the query.
+    async fn aggregate(&self, query: &AggregateQuery) -> Result<Aggregation> {
+        let events = self.scan(&query.events()).await?;
+        Ok(query.aggregate(events.iter()))
+    }
+
+    /// Returns which versions a product's installations were on over time, and how they
+    /// went from one to another.
+    async fn version_adoption(&self, query: &AdoptionQuery) -> Result<Adoption> {
+        let events = self.scan(&query.events()).await?;
+        Ok(query.compute(events))
+    }
+
+    /// Returns the cohort retention matrix of a product's installations.
+    async fn cohorts(&self, query: &CohortQuery) -> Result<CohortMatrix> {
+        let events = self.scan(&query.events()).await?;
+        Ok(query.compute(events))
+    }
+
+    /// Returns how many of a product's installations reached each step of the funnel.
+    async fn funnel(&self, query: &FunnelQuery) -> Result<Funnel> {
+        let events = self.scan(&query.events()).await?;
+        Ok(query.compute(events))
+    }
+
+    /// Computes percentiles and a histogram of a numeric value in the `data` object.
+    async fn percentiles(&self, query: &PercentileQuery) -> Result<Percentiles> {
+        let events = self.scan(&query.events()).await?;
+        Ok(query.compute(events))
+    }
+
+    /// Returns how many events were stored for each product, ordered by the product name.
+    async fn product_stats(&self) -> Result<Vec<ProductStats>>;
+
+    /// Makes sure events older than the configured retention get deleted. This is called
+    /// periodically, so it should be cheap when nothing changed.
+    async fn apply_retention(&self, retention: &RetentionConfig) -> Result<()>;
+
+    /// Makes sure dead letters received more than `days` days ago get deleted. Like
+    /// [`EventStore::apply_retention`], this is called periodically.
+    async fn apply_dead_letter_retention(&self, days: u32) -> Result<()>;
+
+    /// Releases the connections to the backend once the server stopped, after which
+    /// it shouldn't be used anymore.
+    async fn close(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the tables the server needs that don't exist, so the server isn't
+    /// considered ready when the schema is incomplete.
+    async fn missing_tables(&self) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    /// Returns how many events were stored in each bucket of the requested granularity.
+    async fn event_counts(&self, query: &CountsQuery) -> Result<Counts>;
+
+    /// Stores a rejected event.
+    async fn insert_dead_letter(&self, letter: &DeadLetter) -> Result<()>;
+
+    /// Lists the most recent dead letters that match the query.
+    async fn dead_letters(&self, query: &DeadLetterQuery) -> Result<Vec<DeadLetter>>;
+
+    /// Returns a single dead letter, if it exists.
+    async fn dead_letter(&self, id: u64) -> Result<Option<DeadLetter>>;
+}
+
+/// Creates the storage backend that was selected in the configuration.
+pub fn from_config(config: &Config) -> Result<Arc<dyn EventStore>> {
+    match config.storage.unwrap_or_default() {
+        StorageBackend::ClickHouse => {
+            let clickhouse = config.clickhouse.as_ref().ok_or(
+                "`config.clickhouse` is required when using the ClickHouse storage backend",
+            )?;
+
+            Ok(Arc::new(ClickHouse::new(clickhouse)))
+        }
+
+        StorageBackend::SQLite => Ok(Arc::new(SQLite::open(config.sqlite.as_ref())?)),
+        StorageBackend::Memory => {
+            warn!("using the in-memory storage backend, all events will be lost once the server stops!");
+            Ok(Arc::new(Memory::new()))
+        }
+    }
+}
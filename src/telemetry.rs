@@ -13,71 +13,357 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::net::SocketAddr;
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Instant};
 
 use actix_web::{
+    body::BoxBody,
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::header::HeaderMap,
     middleware::Logger,
     web::{self, Data},
-    App, HttpServer,
+    App, Error, HttpServer,
 };
+use opentelemetry::{global, propagation::Extractor};
+use tracing::{field::Empty, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
-use crate::{clickhouse::ClickHouse, config::Config, routes, snowflake::Snowflake};
+use crate::{
+    config::{Config, Reload, SharedConfig},
+    dead_letters::DeadLetters,
+    health::Health,
+    metrics::METRICS,
+    migrations, retention, routes, setup_utils,
+    shutdown::{self, Shutdown},
+    snowflake::Snowflake,
+    stats::EventCount,
+    storage::EventStore,
+    transparency::Reports,
+};
+
+/// Reads the W3C trace context (`traceparent` and `tracestate`) from the request headers.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct TelemetryServer {
-    pub config: &'static Config,
-    pub clickhouse: ClickHouse,
+    pub config: SharedConfig,
+    pub store: Arc<dyn EventStore>,
+    pub dead_letters: DeadLetters,
+    pub health: Health,
+    pub event_count: EventCount,
+    pub transparency: Reports,
+    pub shutdown: Shutdown,
     pub snowflake: Snowflake,
 }
 
 impl TelemetryServer {
-    pub fn new(clickhouse: ClickHouse) -> TelemetryServer {
+    pub fn new(store: Arc<dyn EventStore>) -> TelemetryServer {
+        TelemetryServer::with_config(Config::get().clone(), store)
+    }
+
+    /// Creates a server with the given configuration rather than the global one,
+    /// which is useful for tests.
+    pub fn with_config(config: Config, store: Arc<dyn EventStore>) -> TelemetryServer {
         TelemetryServer {
-            config: Config::get(),
-            clickhouse,
+            dead_letters: DeadLetters::new(store.clone(), config.dead_letters.as_ref()),
+            health: Health::new(store.clone(), config.health.as_ref()),
+            event_count: EventCount::new(store.clone()),
+            transparency: Reports::new(store.clone()),
+            config: SharedConfig::new(config),
+            shutdown: Shutdown::new(),
+            store,
             snowflake: Snowflake::new(),
         }
     }
 
-    pub async fn launch(self) -> Result<(), Box<dyn std::error::Error>> {
-        info!("checking if clickhouse conn is safe");
+    /// Registers every route that the server handles.
+    pub fn routes(cfg: &mut web::ServiceConfig) {
+        cfg.route("/", web::get().to(routes::home))
+            .route("/stats", web::get().to(routes::stats))
+            .route("/healthz", web::get().to(routes::healthz))
+            .route("/readyz", web::get().to(routes::readyz))
+            .route("/metrics", web::get().to(routes::metrics))
+            .route("/track", web::post().to(routes::send))
+            .route("/transparency", web::get().to(routes::transparency))
+            .route(
+                "/transparency.json",
+                web::get().to(routes::transparency_json),
+            )
+            .route("/admin/dead-letters", web::get().to(routes::dead_letters))
+            .route("/admin/retention", web::get().to(routes::retention))
+            .route(
+                "/admin/config/reload",
+                web::post().to(routes::reload_config),
+            )
+            .route("/v1/events", web::get().to(routes::events))
+            .route("/v1/counts", web::get().to(routes::counts))
+            .route("/v1/aggregate", web::get().to(routes::aggregate))
+            .route("/v1/versions", web::get().to(routes::versions))
+            .route("/v1/cohorts", web::get().to(routes::cohorts))
+            .route("/v1/funnel", web::post().to(routes::funnel))
+            .route("/v1/percentiles", web::get().to(routes::percentiles))
+            .route("/dashboard", web::get().to(routes::dashboard))
+            .route("/dashboard/login", web::post().to(routes::dashboard_login))
+            .route(
+                "/dashboard/logout",
+                web::post().to(routes::dashboard_logout),
+            )
+            .route(
+                "/dashboard/api/overview",
+                web::get().to(routes::dashboard_overview),
+            )
+            .route(
+                "/dashboard/assets/{name}",
+                web::get().to(routes::dashboard_asset),
+            )
+            .route(
+                "/admin/dead-letters/{id}",
+                web::get().to(routes::dead_letter),
+            );
+    }
+
+    /// Middleware that wraps every request in a span, which continues the trace of its
+    /// `traceparent` header if it has one, and records how many requests each route handled
+    /// and how long they took.
+    pub fn observe<S>(
+        req: ServiceRequest,
+        srv: &S,
+    ) -> impl Future<Output = Result<ServiceResponse<BoxBody>, Error>>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error>,
+    {
+        let started = Instant::now();
+        let method = req.method().to_string();
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+
+        let span = tracing::info_span!(
+            "http.request",
+            otel.name = %format!("{} {}", method, route),
+            otel.kind = "server",
+            http.request.method = %method,
+            http.route = %route,
+            http.response.status_code = Empty,
+        );
+
+        span.set_parent(global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(req.headers()))
+        }));
+
+        let res = span.in_scope(|| srv.call(req));
+        async move {
+            let res = res.await?;
+            tracing::Span::current().record("http.response.status_code", res.status().as_u16());
+            METRICS.observe_request(&method, &route, res.status(), started.elapsed());
+
+            Ok(res)
+        }
+        .instrument(span)
+    }
+
+    /// Re-reads the configuration and applies the sections that can be reloaded, which
+    /// happens on `SIGHUP` and `POST /admin/config/reload`.
+    pub fn reload(&self) -> Result<Reload, Vec<String>> {
+        let reload = Config::read().and_then(|config| self.config.reload(config));
 
-        let clickhouse = self.clickhouse.clone();
-        let result = clickhouse.ping().await;
-        if let Err(error) = result {
+        let reload = match reload {
+            Ok(reload) => reload,
+            Err(errors) => {
+                error!("configuration wasn't reloaded: {}", errors.join("; "));
+                return Err(errors);
+            }
+        };
+
+        log::set_max_level(setup_utils::log_level(&self.config.get()));
+        self.transparency.invalidate();
+        info!(
+            "reloaded configuration ({} changed)",
+            if reload.reloaded.is_empty() {
+                "nothing".to_string()
+            } else {
+                reload.reloaded.join(", ")
+            }
+        );
+
+        if !reload.restart_required.is_empty() {
+            warn!(
+                "{} changed, but the server has to be restarted for it to apply",
+                reload.restart_required.join(", ")
+            );
+        }
+
+        Ok(reload)
+    }
+
+    pub async fn launch(self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // settings that aren't reloadable are read once, from the configuration the
+        // server started with.
+        let config = self.config.get();
+        info!("checking if {} conn is safe", self.store.name());
+
+        if let Err(error) = self.store.ping().await {
             error!("{}", error);
-            panic!("Couldn't ping ClickHouse, view above error on why.");
+            panic!(
+                "Couldn't ping {}, view above error on why.",
+                self.store.name()
+            );
+        }
+
+        let run_migrations = config
+            .migrations
+            .as_ref()
+            .and_then(|m| m.run_on_startup)
+            .unwrap_or(false);
+
+        if run_migrations {
+            let applied = migrations::up(self.store.as_ref(), false).await?;
+            info!("applied {} pending migrations", applied.len());
+        } else {
+            let pending = migrations::pending(self.store.as_ref()).await?;
+            if !pending.is_empty() {
+                error!(
+                    "{} has {} pending migrations, run `telemetry-server migrate up` or enable `config.migrations.run_on_startup`",
+                    self.store.name(),
+                    pending.len()
+                );
+
+                return Err("schema is out of date".into());
+            }
         }
 
+        // the retention is applied before we start accepting events, so expired
+        // events are never served, and then re-applied in the background.
+        retention::apply(self.store.as_ref(), &self.dead_letters, &self.config).await;
+        retention::spawn(
+            self.store.clone(),
+            self.dead_letters.clone(),
+            self.config.clone(),
+        );
+
         info!("launching http service...");
-        let addr = match &self.config.host {
+        let addr = match &config.host {
             Some(host) => {
-                let port = self.config.port.unwrap_or(1234);
+                let port = config.port.unwrap_or(1234);
                 format!("{}:{}", host, port)
                     .parse::<SocketAddr>()
                     .expect("Unable to parse to SocketAddr.")
             }
             None => {
-                let port = self.config.port.unwrap_or(1234);
+                let port = config.port.unwrap_or(1234);
                 format!("0.0.0.0:{}", port)
                     .parse::<SocketAddr>()
                     .expect("Unable to parse to SocketAddr.")
             }
         };
 
+        let timeout = config
+            .shutdown
+            .as_ref()
+            .and_then(|s| s.timeout_seconds)
+            .unwrap_or(30);
+
         info!("now running in address {addr}!");
-        HttpServer::new(move || {
+        let server = self.clone();
+        let http = HttpServer::new(move || {
             App::new()
-                .app_data(Data::new(self.clone()))
+                .app_data(Data::new(server.clone()))
+                .wrap_fn(TelemetryServer::observe)
                 .wrap(Logger::new("%r %s [%b bytes; %D ms]").log_target("actix::http::request"))
-                .route("/", web::get().to(routes::home))
-                .route("/stats", web::get().to(routes::stats))
-                .route("/track", web::post().to(routes::send))
+                .configure(TelemetryServer::routes)
         })
+        .disable_signals()
+        .shutdown_timeout(timeout)
         .bind(addr)?
-        .run()
-        .await?;
+        .run();
+
+        // we handle the signals ourselves, so that `/track` refuses new events before the
+        // server stops accepting connections.
+        let handle = http.handle();
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            let signal = shutdown::signal().await;
+            shutdown.begin();
+            info!(
+                "received {}, waiting up to {}s for in-flight requests ({} events) to finish...",
+                signal,
+                timeout,
+                shutdown.in_flight()
+            );
+
+            handle.stop(true).await;
+        });
+
+        #[cfg(unix)]
+        {
+            let server = self.clone();
+            tokio::spawn(async move {
+                use tokio::signal::unix::{signal, SignalKind};
+
+                let mut hangup = signal(SignalKind::hangup()).expect("Unable to listen for SIGHUP");
+                while hangup.recv().await.is_some() {
+                    info!("received SIGHUP, reloading configuration...");
+                    let _ = server.reload();
+                }
+            });
+        }
+
+        http.await?;
+
+        // requests that didn't finish within the deadline were dropped by actix, and each
+        // of them was logged when it was.
+        let aborted = self.shutdown.aborted();
+        if aborted > 0 {
+            warn!(
+                "{} events were aborted before they could be stored",
+                aborted
+            );
+        }
+
+        info!("closing {} connections...", self.store.name());
+        self.store.close().await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use opentelemetry::{
+        propagation::TextMapPropagator,
+        trace::{TraceContextExt, TraceId},
+    };
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+
+    use super::HeaderExtractor;
+
+    #[test]
+    fn extracts_traceparent() {
+        let req = TestRequest::get()
+            .insert_header((
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            ))
+            .to_srv_request();
+
+        let context = TraceContextPropagator::new().extract(&HeaderExtractor(req.headers()));
+        let span = context.span();
+        let span = span.span_context();
+
+        assert!(span.is_remote() && span.is_sampled());
+        assert_eq!(
+            span.trace_id(),
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap()
+        );
+    }
+}
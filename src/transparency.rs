@@ -0,0 +1,262 @@
+// 🐻‍❄️🌧️ Noelware Telemetry: Telemetry project for Noelware to capture anonymous data about our running products.
+// Copyright 2022 Noelware <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{
+    config::Config,
+    metrics::OTHER_PRODUCT,
+    storage::{EventStore, Result},
+};
+
+/// How long the report is served from the cache, since generating it goes through every
+/// stored event and anyone can request it.
+pub const CACHE_FOR: Duration = Duration::from_secs(5 * 60);
+
+/// Fields that every event has, regardless of the product that sent it.
+pub const ENVELOPE: &[(&str, &str)] = &[
+    ("product", "Name of the product that sent the event."),
+    ("vendor", "Who distributes the product, i.e, Noelware."),
+    ("version", "Version of the product."),
+    ("os", "Operating system the product runs on, i.e, linux."),
+    ("arch", "CPU architecture the product runs on, i.e, x86_64."),
+    (
+        "distribution",
+        "How the product was installed, i.e, docker or helm.",
+    ),
+    (
+        "installation_id",
+        "Optional random identifier that the installation generates for itself, which isn't derived from anything about it.",
+    ),
+    ("fired_at", "When the event was received."),
+];
+
+const TEMPLATE: &str = include_str!("../assets/transparency.html");
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Field {
+    pub name: String,
+    pub description: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ProductReport {
+    pub product: String,
+    pub description: Option<String>,
+
+    /// Fields of the `data` object that the product declared, or `None` for the products
+    /// that sent events without being declared in the configuration.
+    pub fields: Option<Vec<Field>>,
+    pub retention_days: Option<u32>,
+    pub events: u64,
+    pub oldest_event: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Transparency {
+    pub envelope: Vec<Field>,
+    pub default_retention_days: Option<u32>,
+    pub products: Vec<ProductReport>,
+    pub generated_at: DateTime<Utc>,
+}
+
+/// Caches the transparency report for [`CACHE_FOR`], or until the configuration is reloaded.
+#[derive(Debug, Clone)]
+pub struct Reports {
+    store: Arc<dyn EventStore>,
+    last: Arc<Mutex<Option<(Instant, Transparency)>>>,
+    stale: Arc<AtomicBool>,
+}
+
+impl Reports {
+    pub fn new(store: Arc<dyn EventStore>) -> Reports {
+        Reports {
+            store,
+            last: Arc::new(Mutex::new(None)),
+            stale: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns the cached report, or generates it if it expired. Concurrent callers wait
+    /// on the same report rather than generating their own.
+    pub async fn get(&self, config: &Config) -> Result<Transparency> {
+        let mut last = self.last.lock().await;
+        let stale = self.stale.swap(false, Ordering::SeqCst);
+        if let Some((at, report)) = last.as_ref() {
+            if !stale && at.elapsed() < CACHE_FOR {
+                return Ok(report.clone());
+            }
+        }
+
+        let report = report(self.store.as_ref(), config).await?;
+        *last = Some((Instant::now(), report.clone()));
+        Ok(report)
+    }
+
+    /// Makes the next request generate the report again, since the products (or their
+    /// retention) might have changed.
+    pub fn invalidate(&self) {
+        self.stale.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Describes what is collected for each product, from the configuration of the server
+/// and the events it has. Only products declared in `config.products` are listed, so
+/// clients can't put whatever they want on the page; the events of every other product
+/// are counted under a single "other" entry.
+pub async fn report(store: &dyn EventStore, config: &Config) -> Result<Transparency> {
+    let retention = config.retention.clone().unwrap_or_default();
+    let declared = config.products.clone().unwrap_or_default();
+    let stats = store.product_stats().await?;
+
+    let mut products = declared
+        .into_iter()
+        .map(|(product, config)| {
+            let stats = stats.iter().find(|s| s.product == product);
+            ProductReport {
+                description: config.description,
+                fields: Some(
+                    config
+                        .fields
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|(name, description)| Field { name, description })
+                        .collect(),
+                ),
+                retention_days: retention.days_for(&product),
+                events: stats.map(|s| s.events).unwrap_or_default(),
+                oldest_event: stats.and_then(|s| s.oldest_event),
+                product,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let undeclared = stats
+        .iter()
+        .filter(|s| !products.iter().any(|p| p.product == s.product))
+        .collect::<Vec<_>>();
+
+    if !undeclared.is_empty() {
+        products.push(ProductReport {
+            product: OTHER_PRODUCT.to_string(),
+            description: None,
+            fields: None,
+            retention_days: retention.default_days,
+            events: undeclared.iter().map(|s| s.events).sum(),
+            oldest_event: undeclared.iter().filter_map(|s| s.oldest_event).min(),
+        });
+    }
+
+    Ok(Transparency {
+        envelope: ENVELOPE
+            .iter()
+            .map(|(name, description)| Field {
+                name: name.to_string(),
+                description: description.to_string(),
+            })
+            .collect(),
+        default_retention_days: retention.default_days,
+        products,
+        generated_at: Utc::now(),
+    })
+}
+
+/// Renders the report as the `/transparency` page.
+pub fn render(report: &Transparency) -> String {
+    let mut envelope = String::new();
+    for field in report.envelope.iter() {
+        let _ = write!(
+            envelope,
+            "<tr><td><code>{}</code></td><td>{}</td></tr>",
+            escape(&field.name),
+            escape(&field.description)
+        );
+    }
+
+    let mut products = String::new();
+    for product in report.products.iter() {
+        let _ = write!(products, "<section><h2>{}</h2>", escape(&product.product));
+        if let Some(description) = &product.description {
+            let _ = write!(products, "<p>{}</p>", escape(description));
+        }
+
+        let retention = match product.retention_days {
+            Some(days) => format!("Events are deleted after {} days.", days),
+            None => "Events are kept forever.".into(),
+        };
+
+        let _ = write!(
+            products,
+            "<p>{} {} events are stored{}.</p>",
+            retention,
+            product.events,
+            product
+                .oldest_event
+                .map(|oldest| format!(", the oldest one is from {}", oldest.format("%Y-%m-%d")))
+                .unwrap_or_default()
+        );
+
+        match &product.fields {
+            Some(fields) if fields.is_empty() => {
+                products.push_str("<p>No fields are collected besides the ones above.</p>")
+            }
+            Some(fields) => {
+                products.push_str("<table><tr><th>Field</th><th>Why</th></tr>");
+                for field in fields {
+                    let _ = write!(
+                        products,
+                        "<tr><td><code>data.{}</code></td><td>{}</td></tr>",
+                        escape(&field.name),
+                        escape(&field.description)
+                    );
+                }
+
+                products.push_str("</table>");
+            }
+            None => products.push_str(
+                "<p>Events of products that aren't declared in the configuration, which don't declare the fields they collect.</p>",
+            ),
+        }
+
+        products.push_str("</section>");
+    }
+
+    TEMPLATE
+        .replace("<!-- envelope -->", &envelope)
+        .replace("<!-- products -->", &products)
+        .replace(
+            "<!-- generated_at -->",
+            &report.generated_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+        )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}